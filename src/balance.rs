@@ -0,0 +1,30 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use serde::Deserialize;
+
+#[derive(Deserialize,Clone,Copy,PartialEq,Default,Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Balance {
+    #[default]
+    RoundRobin,
+    LeastConn,
+    Random,
+}
+
+// 按负载均衡策略返回尝试的顺序，第一个失败时依次尝试后面的，loads为每个后端当前的连接数
+pub fn balance_order(balance:Balance,next:&AtomicUsize,loads:&[usize]) -> Vec<usize> {
+    let n = loads.len();
+    if n == 0 {
+        return vec![];
+    }
+    let start = match balance {
+        Balance::Random => rand::random_range(0..n),
+        _ => next.fetch_add(1, Ordering::Relaxed) % n,
+    };
+    let mut order: Vec<usize> = (0..n).map(|i| (start + i) % n).collect();
+    if balance == Balance::LeastConn {
+        // 稳定排序，连接数相同时按轮询顺序
+        order.sort_by_key(|i| loads[*i]);
+    }
+    order
+}
@@ -1,28 +1,296 @@
-use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
-use tcp_tunnel::{load_client_config, xor, EncReader, EncWriter, TcpTunnelClientConfig, CLOSE_ID};
-use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream, sync::Mutex};
-use log::{info, error};
+use std::{collections::{hash_map::Entry, HashMap}, net::SocketAddr, sync::{atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering}, Arc}, time::{Duration, Instant}};
+use tcp_tunnel::{balance::{balance_order, Balance}, cli::Cli, config::read_config, health::{self, HealthCheckConfig}, listen_or_exit, load_client_config, metrics::{self, GaugeGuard}, ping::{self, RttStats, PING_INTERVAL, PING_LEN}, shutdown_signal, proxy_protocol::encode_header, range_addr, ws::ws_connect, xor, BoxedStream, ClientConfig, EncReader, EncWriter, TcpTunnelClientConfig, Transport, Handshake, TunnelStream, TunnelWriter, BOUND_ID, CLOSE_ID, GOAWAY_ID, CONNECTION_ID_START, HEALTH_ID, OPEN_ID, PING_ID, PONG_ID};
+use tokio::{io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt}, net::{tcp::OwnedWriteHalf, TcpStream}, sync::{oneshot, watch, Mutex}};
+use log::{info, error, warn};
 
-async fn client_handle(mut stream:TcpStream,config:(String,TcpTunnelClientConfig)) -> tokio::io::Result<()> {
+enum LocalConn {
+    // 正在连接后端，期间从隧道收到的数据先缓存
+    Connecting(Vec<u8>),
+    Connected(OwnedWriteHalf),
+}
+
+#[derive(Clone,Copy,PartialEq)]
+enum State {
+    Connecting,
+    Authenticating,
+    Up,
+    // 等待reconn秒后重连
+    Backoff,
+}
+
+impl State {
+    fn as_str(&self) -> &'static str {
+        match self {
+            State::Connecting => "connecting",
+            State::Authenticating => "authenticating",
+            State::Up => "up",
+            State::Backoff => "backoff",
+        }
+    }
+}
+
+// 隧道连接状态，供client status查询
+struct TunnelState {
+    state: State,
+    // 进入当前状态的时间，up时即为连接时长
+    since: Instant,
+    last_error: Option<String>,
+    // server实际监听的地址，旧版server不发送
+    bound: Option<String>,
+}
+
+struct Backends {
+    addrs: Vec<String>,
+    balance: Balance,
+    connect_timeout: Duration,
+    next: AtomicUsize,
+    active: Vec<Arc<AtomicUsize>>,
+    healthy: Vec<AtomicBool>,
+    // 任意一个后端健康即为健康，变化时上报server
+    status: watch::Sender<bool>,
+    // prometheus指标
+    healthy_gauge: Arc<AtomicU64>,
+    active_conns: Arc<AtomicU64>,
+    bytes_in: Arc<AtomicU64>,
+    bytes_out: Arc<AtomicU64>,
+    // 与server之间的往返时间，重连后继续累计
+    rtt: RttStats,
+    state: std::sync::Mutex<TunnelState>,
+}
+
+impl Backends {
+    fn new(tunnel_name:&str,config:&TcpTunnelClientConfig) -> Self {
+        let backends = Backends {
+            addrs: config.local_addr.clone(),
+            balance: config.balance,
+            connect_timeout: Duration::from_secs(config.connect_timeout),
+            next: AtomicUsize::new(0),
+            active: config.local_addr.iter().map(|_| Arc::new(AtomicUsize::new(0))).collect(),
+            healthy: config.local_addr.iter().map(|_| AtomicBool::new(true)).collect(),
+            status: watch::Sender::new(true),
+            healthy_gauge: metrics::BACKEND_HEALTHY.with(&[("tunnel",tunnel_name)]),
+            active_conns: metrics::ACTIVE_CONNECTIONS.with(&[("tunnel",tunnel_name)]),
+            bytes_in: metrics::BYTES_IN.with(&[("tunnel",tunnel_name)]),
+            bytes_out: metrics::BYTES_OUT.with(&[("tunnel",tunnel_name)]),
+            rtt: RttStats::default(),
+            state: std::sync::Mutex::new(TunnelState { state: State::Connecting, since: Instant::now(), last_error: None, bound: None }),
+        };
+        backends.healthy_gauge.store(1, Ordering::Relaxed);
+        backends
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, TunnelState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn set_state(&self, state: State) {
+        let mut s = self.state();
+        s.state = state;
+        s.since = Instant::now();
+        // 重连后server可能分配不同的端口
+        if state == State::Connecting {
+            s.bound = None;
+        }
+    }
+
+    fn set_error(&self, e: String) {
+        self.state().last_error = Some(e);
+    }
+
+    fn connections(&self) -> usize {
+        self.active.iter().map(|a| a.load(Ordering::Relaxed)).sum()
+    }
+}
+
+// 运行中的tunnel，供控制socket查询
+type Tunnels = Arc<std::sync::Mutex<HashMap<String, Arc<Backends>>>>;
+
+async fn health_check_loop(tunnel_name:&str,conf:&HealthCheckConfig,backends:&Backends) {
+    loop {
+        for (i,addr) in backends.addrs.iter().enumerate() {
+            // 端口范围只检查第一个端口
+            let r = health::check(conf, &range_addr(addr, 0).unwrap_or_default()).await;
+            let was_healthy = backends.healthy[i].swap(r.is_ok(), Ordering::Relaxed);
+            match r {
+                Err(e) if was_healthy => error!(tunnel = tunnel_name; "tunnel {} backend {} health check failed: {}",tunnel_name,addr,e),
+                Ok(_) if !was_healthy => info!(tunnel = tunnel_name; "tunnel {} backend {} health check recovered",tunnel_name,addr),
+                _ => {}
+            }
+        }
+        let healthy = backends.healthy.iter().any(|h| h.load(Ordering::Relaxed));
+        backends.healthy_gauge.store(healthy as u64, Ordering::Relaxed);
+        backends.status.send_if_modified(|status| {
+            let modified = *status != healthy;
+            *status = healthy;
+            modified
+        });
+        tokio::time::sleep(Duration::from_secs(conf.interval)).await;
+    }
+}
+
+// server通知的公网连接信息
+struct ConnInfo {
+    // 在remote_addr端口范围中的位置
+    port_offset: u16,
+    proxy_header: Option<Vec<u8>>,
+}
+
+// 所有tunnel的后端连接数，退出时等待为0
+static ACTIVE_CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+// 连接结束时减少后端的连接数
+struct BackendGuard(Arc<AtomicUsize>,Arc<AtomicU64>);
+
+impl Drop for BackendGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+        self.1.fetch_sub(1, Ordering::Relaxed);
+        ACTIVE_CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// port_offset为公网连接在remote_addr端口范围中的位置
+async fn connect_backend(tunnel_name:&str,id:u32,port_offset:u16,backends:&Backends) -> tokio::io::Result<(TcpStream,String,BackendGuard)> {
+    let loads: Vec<usize> = backends.active.iter().map(|a| a.load(Ordering::Relaxed)).collect();
+    let mut order = balance_order(backends.balance, &backends.next, &loads);
+    // 健康检查失败的后端放到最后尝试
+    order.sort_by_key(|i| !backends.healthy[*i].load(Ordering::Relaxed));
+    let mut last_err = tokio::io::Error::new(tokio::io::ErrorKind::NotFound, "no backend configured");
+    for i in order {
+        let Some(addr) = range_addr(&backends.addrs[i], port_offset) else {
+            error!(tunnel = tunnel_name, connection = id; "tunnel {} connection {} port offset {} is out of range {}",tunnel_name,id,port_offset,backends.addrs[i]);
+            last_err = tokio::io::Error::new(tokio::io::ErrorKind::InvalidInput, format!("port offset {} is out of range", port_offset));
+            continue;
+        };
+        // 每次连接时解析local_addr，依次尝试解析出的所有地址
+        let r = tokio::time::timeout(backends.connect_timeout, TcpStream::connect(addr.as_str())).await
+            .unwrap_or_else(|_| Err(tokio::io::Error::new(tokio::io::ErrorKind::TimedOut, "connect timeout")));
+        match r {
+            Ok(stream) => {
+                backends.active[i].fetch_add(1, Ordering::Relaxed);
+                backends.active_conns.fetch_add(1, Ordering::Relaxed);
+                ACTIVE_CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+                info!(tunnel = tunnel_name, connection = id; "tunnel {} connection {} connected to {}",tunnel_name,id,addr);
+                metrics::CONNECTIONS.inc(&[("tunnel",tunnel_name),("result","accepted")]);
+                return Ok((stream,addr,BackendGuard(backends.active[i].clone(),backends.active_conns.clone())));
+            }
+            Err(e) => {
+                error!(tunnel = tunnel_name, connection = id; "tunnel {} connection {} connect to {} error {}",tunnel_name,id,addr,e);
+                last_err = e;
+            }
+        }
+    }
+    Err(last_err)
+}
+
+async fn local_connection(tunnel_name:String,id:u32,conn_info:ConnInfo,backends:Arc<Backends>,tunnel_writer:Arc<Mutex<TunnelWriter>>,connections_writers:Arc<Mutex<HashMap<u32, LocalConn>>>,key:String) {
+    let s = connect_backend(&tunnel_name, id, conn_info.port_offset, &backends).await;
+    let mut write_data = vec![];
+    let mut enc_writer = EncWriter::new(key);
+    match s {
+        Ok((stream,addr,_guard)) => {
+            let (mut reader,mut writer) = stream.into_split();
+            let mut l = connections_writers.lock().await;
+            match l.remove(&id) {
+                Some(LocalConn::Connecting(pending)) => {
+                    if let Some(header) = conn_info.proxy_header {
+                        let _ = writer.write_all(&header).await;
+                    }
+                    let _ = writer.write_all(&pending).await;
+                    backends.bytes_in.fetch_add(pending.len() as u64, Ordering::Relaxed);
+                    l.insert(id, LocalConn::Connected(writer));
+                }
+                _ => {
+                    info!(tunnel = tunnel_name.as_str(), connection = id; "tunnel {} connection {} closed before connected to {}",tunnel_name,id,addr);
+                    return;
+                }
+            }
+            drop(l);
+            let mut buf = [0;4096];
+            loop {
+                let r = reader.read(&mut buf).await;
+                write_data.clear();
+                let mut w = tunnel_writer.lock().await;
+                match r {
+                    Ok(n) => {
+                        if n == 0 {
+                            error!(tunnel = tunnel_name.as_str(), connection = id; "tunnel {} connection {} read date from {} data length 0",tunnel_name,id,addr);
+                            write_data.extend_from_slice(&CLOSE_ID.to_be_bytes());
+                            write_data.extend_from_slice(&id.to_be_bytes());
+                            let _ = enc_writer.write_to_tunnel(&mut *w, &write_data).await;
+                            break;
+                        }
+                        info!(tunnel = tunnel_name.as_str(), connection = id; "tunnel {} connection {} write {} bytes data to tunnel",tunnel_name,id,n);
+                        backends.bytes_out.fetch_add(n as u64, Ordering::Relaxed);
+                        write_data.extend_from_slice(&id.to_be_bytes());
+                        write_data.extend_from_slice(&buf[..n]);
+                        let _ = enc_writer.write_to_tunnel(&mut *w, &write_data).await;
+                    },
+                    Err(e) => {
+                        error!(tunnel = tunnel_name.as_str(), connection = id; "tunnel {} connection {} read data from {} error {}",tunnel_name,id,addr,e);
+                        write_data.extend_from_slice(&CLOSE_ID.to_be_bytes());
+                        write_data.extend_from_slice(&id.to_be_bytes());
+                        let _ = enc_writer.write_to_tunnel(&mut *w, &write_data).await;
+                        break;
+                    }
+                }
+                drop(w);
+            }
+
+        },
+        Err(e) => {
+            connections_writers.lock().await.remove(&id);
+            let mut w = tunnel_writer.lock().await;
+            write_data.clear();
+            write_data.extend_from_slice(&CLOSE_ID.to_be_bytes());
+            write_data.extend_from_slice(&id.to_be_bytes());
+            let _ = enc_writer.write_to_tunnel(&mut *w, &write_data).await;
+            error!(tunnel = tunnel_name.as_str(), connection = id; "tunnel {} connection {} all backends failed, last error {}",tunnel_name,id,e);
+            metrics::CONNECTIONS.inc(&[("tunnel",&tunnel_name),("result","failed")]);
+        }
+    }
+}
+
+// client_handle结束或被中止（如重新加载配置）时中止所有子任务，释放隧道连接
+struct TaskGroup(Vec<tokio::task::JoinHandle<()>>);
+
+impl Drop for TaskGroup {
+    fn drop(&mut self) {
+        for h in self.0.iter() {
+            h.abort();
+        }
+    }
+}
+
+// 收到GOAWAY时通过goaway通知重连，本连接继续传输已有连接的数据
+async fn client_handle(mut stream:BoxedStream,config:(String,TcpTunnelClientConfig),backends:Arc<Backends>,goaway:oneshot::Sender<()>,shutdown:watch::Receiver<bool>) -> tokio::io::Result<()> {
 
     let tunnel_name = config.0;
     let config = config.1;
 
-    let name_len = tunnel_name.as_bytes().len();
+    let name_len = tunnel_name.len();
     let mut data = vec![name_len as u8];
-    data.extend_from_slice(&tunnel_name.as_bytes());
-    let remote_addr = config.remote_addr.to_string();
+    data.extend_from_slice(tunnel_name.as_bytes());
+    let mut handshake = Handshake::new(config.remote_addr.clone().unwrap_or_default());
+    if !config.custom_domains.is_empty() {
+        handshake.options.push(("domains".to_string(), config.custom_domains.join(",")));
+    }
+    if let Some(host) = &config.host_header_rewrite {
+        handshake.options.push(("host_rewrite".to_string(), host.clone()));
+    }
     let mut auth_data = vec![];
-    xor(remote_addr.as_bytes(), config.key.as_bytes(), &mut auth_data);
+    xor(&handshake.encode(), config.key.as_bytes(), &mut auth_data);
     data.append(&mut auth_data);
-    let _ = stream.write_all(&data).await?;
-    info!("tunnel {} auth finished",tunnel_name);
+    stream.write_all(&data).await?;
+    stream.flush().await?;
+    info!(tunnel = tunnel_name.as_str(); "tunnel {} auth finished",tunnel_name);
+    let _active_tunnel = GaugeGuard::new(metrics::ACTIVE_TUNNELS.with(&[]));
 
-    let (mut tunnel_reader,tunnel_writer) = stream.into_split();
+    let (mut tunnel_reader,tunnel_writer) = tokio::io::split(stream);
     let tunnel_writer = Arc::new(Mutex::new(tunnel_writer));
-    let mut handles: Vec<tokio::task::JoinHandle<()>> = vec![];
+    let mut handles = TaskGroup(vec![]);
 
-    let connections_writers: Arc<Mutex<HashMap<u32, tokio::net::tcp::OwnedWriteHalf>>> = Arc::new(Mutex::new(HashMap::new()));
+    let connections_writers: Arc<Mutex<HashMap<u32, LocalConn>>> = Arc::new(Mutex::new(HashMap::new()));
     
     let key = config.key;
 
@@ -30,151 +298,542 @@ async fn client_handle(mut stream:TcpStream,config:(String,TcpTunnelClientConfig
     let mut enc_writer = EncWriter::new(key.clone());
     let mut len_bytes = [0;4];
 
+    // 上报后端健康状态，退出时上报不健康，server不再分配新连接
+    let report_health = config.health_check.is_some();
+    let mut status = backends.status.subscribe();
+    let mut shutdown = shutdown.clone();
+    let health_writer = tunnel_writer.clone();
+    let health_tunnel_name = tunnel_name.clone();
+    let mut health_enc_writer = EncWriter::new(key.clone());
+    handles.0.push(tokio::spawn(async move {
+        let mut write_data = vec![];
+        loop {
+            let draining = *shutdown.borrow_and_update();
+            let healthy = *status.borrow_and_update() && !draining;
+            if report_health || draining {
+                write_data.clear();
+                write_data.extend_from_slice(&HEALTH_ID.to_be_bytes());
+                write_data.push(healthy as u8);
+                let mut w = health_writer.lock().await;
+                if health_enc_writer.write_to_tunnel(&mut *w, &write_data).await.is_err() {
+                    break;
+                }
+                drop(w);
+                info!(tunnel = health_tunnel_name.as_str(); "tunnel {} report health status {} to server",health_tunnel_name,healthy);
+            }
+            if draining {
+                break;
+            }
+            tokio::select! {
+                r = status.changed() => if r.is_err() { break },
+                r = shutdown.changed() => if r.is_err() { break },
+            }
+        }
+    }));
+
+    // 收到新格式的PING说明server会回复PONG，之后client也定时PING
+    let server_pong = Arc::new(AtomicBool::new(false));
+    let ping_server_pong = server_pong.clone();
+    let ping_writer = tunnel_writer.clone();
+    let ping_backends = backends.clone();
+    let mut ping_enc_writer = EncWriter::new(key.clone());
+    handles.0.push(tokio::spawn(async move {
+        while !ping_server_pong.load(Ordering::Relaxed) {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+        // 与server的PING错开，避免紧跟在PONG之后发送
+        tokio::time::sleep(PING_INTERVAL / 2).await;
+        loop {
+            let mut write_data = PING_ID.to_be_bytes().to_vec();
+            write_data.extend_from_slice(&ping::encode(ping_backends.rtt.next_seq.fetch_add(1, Ordering::Relaxed)));
+            let mut w = ping_writer.lock().await;
+            if ping_enc_writer.write_to_tunnel(&mut *w, &write_data).await.is_err() {
+                break;
+            }
+            drop(w);
+            tokio::time::sleep(PING_INTERVAL).await;
+        }
+    }));
+
+    // 收到过OPEN或BOUND说明是新版server，连接由OPEN建立
+    let mut new_server = false;
+    // 新版server认证成功后先发送BOUND，旧版server以收到第一个帧为准
+    let mut up = false;
+    let mut goaway = Some(goaway);
     let mut write_data = vec![];
     let mut data  = vec![];
     loop {
         data.clear();
         let r = enc_reader.read_from_tunnel(&mut tunnel_reader, &mut data).await;
-        if r.is_err() {
-            error!("error while read from tunnel {} stream : {}",tunnel_name,r.err().unwrap());
+        if let Err(e) = r {
+            // server认证失败时直接关闭连接，没收到任何帧就断开视为认证失败
+            if !up {
+                return Err(tokio::io::Error::new(tokio::io::ErrorKind::PermissionDenied, format!("server closed the tunnel before the first frame, authentication failed ({})", e)));
+            }
+            error!(tunnel = tunnel_name.as_str(); "error while read from tunnel {} stream : {}",tunnel_name,e);
+            // 收到GOAWAY后旧连接的错误不影响新连接的状态
+            if goaway.is_some() {
+                backends.set_error(format!("read from server: {}", e));
+            }
             break;
         }
-
-        for i in 0..4 {
-            len_bytes[i] = data[i];
+        if !up {
+            up = true;
+            backends.set_state(State::Up);
         }
+
+        len_bytes.copy_from_slice(&data[..4]);
         let id = u32::from_be_bytes(len_bytes);
         let data = &data[4..];
         
-        if id != 0 {
-            let mut l = connections_writers.lock().await;
-            if id == CLOSE_ID {
-                for i in 0..4 {
-                    len_bytes[i] = data[i];
-                }
-                let id = u32::from_be_bytes(len_bytes);
-                if let Some(mut s) = l.remove(&id) {
+        if id == PING_ID {
+            // 旧版server的PING内容为"PING"，不回复
+            if data.len() == PING_LEN {
+                server_pong.store(true, Ordering::Relaxed);
+                let mut w = tunnel_writer.lock().await;
+                write_data.clear();
+                write_data.extend_from_slice(&PONG_ID.to_be_bytes());
+                write_data.extend_from_slice(data);
+                let _ = enc_writer.write_to_tunnel(&mut *w, &write_data).await;
+            }
+            continue;
+        }
+        if id == PONG_ID {
+            if let Some(rtt) = backends.rtt.record(data) {
+                info!(tunnel = tunnel_name.as_str(); "tunnel {} ping rtt {:.1}ms, {}",tunnel_name,rtt.as_secs_f64() * 1000.0,backends.rtt);
+                backends.rtt.export(&tunnel_name);
+            }
+            continue;
+        }
+        let mut l = connections_writers.lock().await;
+        if id == OPEN_ID {
+            new_server = true;
+            let Some(id) = data.first_chunk::<4>().map(|b| u32::from_be_bytes(*b)) else {
+                error!(tunnel = tunnel_name.as_str(); "tunnel {} server sent short open frame, ignored",tunnel_name);
+                continue;
+            };
+            let peer = String::from_utf8_lossy(&data[4..]).to_string();
+            // "源地址 目标地址 端口范围中的位置"
+            let mut parts = peer.split(' ');
+            let addrs = parts.next().and_then(|a| a.parse::<SocketAddr>().ok()).zip(parts.next().and_then(|a| a.parse::<SocketAddr>().ok()));
+            let offset = parts.next().and_then(|o| o.parse::<u16>().ok());
+            if let Entry::Vacant(e) = l.entry(id) {
+                e.insert(LocalConn::Connecting(vec![]));
+                info!(tunnel = tunnel_name.as_str(), connection = id; "tunnel {} new connection {} from {}",tunnel_name,id,peer);
+                let proxy_header = config.proxy_protocol.map(|v| encode_header(v, addrs));
+                let conn_info = ConnInfo { port_offset: offset.unwrap_or(0), proxy_header };
+                let h = tokio::spawn(local_connection(tunnel_name.clone(), id, conn_info, backends.clone(), tunnel_writer.clone(), connections_writers.clone(), key.clone()));
+                handles.0.push(h);
+            }
+        } else if id == GOAWAY_ID {
+            info!(tunnel = tunnel_name.as_str(); "tunnel {} server is going away, reconnecting",tunnel_name);
+            if let Some(goaway) = goaway.take() {
+                let _ = goaway.send(());
+            }
+        } else if id == BOUND_ID {
+            new_server = true;
+            // 只注册域名时没有监听地址
+            if !data.is_empty() {
+                let bound = String::from_utf8_lossy(data).to_string();
+                info!(tunnel = tunnel_name.as_str(); "tunnel {} server listening on {}",tunnel_name,bound);
+                backends.state().bound = Some(bound);
+            }
+        } else if id < CONNECTION_ID_START && id != CLOSE_ID {
+            // 不认识的控制帧，忽略
+        } else if id == CLOSE_ID {
+            let Some(id) = data.first_chunk::<4>().map(|b| u32::from_be_bytes(*b)) else {
+                error!(tunnel = tunnel_name.as_str(); "tunnel {} server sent short close frame, ignored",tunnel_name);
+                continue;
+            };
+            if let Some(conn) = l.remove(&id) {
+                if let LocalConn::Connected(mut s) = conn {
                     let _ = s.shutdown().await;
-                    info!("tunnel {} close connection {}",tunnel_name,id);
                 }
-            } else {
-                if let Some(s) = l.get_mut(&id) {
-                    let mut err: bool = false;
+                info!(tunnel = tunnel_name.as_str(), connection = id; "tunnel {} close connection {}",tunnel_name,id);
+            }
+        } else {
+            match l.get_mut(&id) {
+                Some(LocalConn::Connecting(pending)) => {
+                    pending.extend_from_slice(data);
+                }
+                Some(LocalConn::Connected(s)) => {
                     let r = s.write_all(data).await;
-                    err = r.is_err();
-                    if err {
-                        let mut s = l.remove(&id).unwrap();
-                        let _ = s.shutdown().await;
+                    backends.bytes_in.fetch_add(data.len() as u64, Ordering::Relaxed);
+                    if r.is_err() {
+                        if let Some(LocalConn::Connected(mut s)) = l.remove(&id) {
+                            let _ = s.shutdown().await;
+                        }
                         let mut w = tunnel_writer.lock().await;
                         write_data.clear();
                         write_data.extend_from_slice(&CLOSE_ID.to_be_bytes());
                         write_data.extend_from_slice(&id.to_be_bytes());
-                        let _ = enc_writer.write_to_tunnel(&mut w, &write_data);
+                        let _ = enc_writer.write_to_tunnel(&mut *w, &write_data).await;
                     }
-                    drop(l);
-                } else {
-                    drop(l);
-                    let addr = config.local_addr;
-                    info!("tunnel {} new connection {} to {}",tunnel_name,id,addr);
-                    let connections_to_tunnel_writer = tunnel_writer.clone();
-                    let shared_connections_writers = connections_writers.clone();
-                    let data_from_tunnel = data.to_vec();
-                    let connections_tunnel_name = tunnel_name.clone();
-                    let key = key.clone();
-                    let h = tokio::spawn(async move {
-                        let s = TcpStream::connect(addr).await;
-                        let mut write_data = vec![];
-                        let mut enc_writer = EncWriter::new(key);
-                        match s {
-                            Ok(mut stream) => {
-                                let mut l = shared_connections_writers.lock().await;
-                                let _ = stream.write_all(&data_from_tunnel).await;
-                                let (mut reader,writer) = stream.into_split();
-                                l.insert(id, writer);
-                                drop(l);
-                                let mut buf = [0;4096];
-                                loop {
-                                    let r = reader.read(&mut buf).await;
-                                    write_data.clear();
-                                    let mut w = connections_to_tunnel_writer.lock().await;
-                                    match r {
-                                        Ok(n) => {
-                                            if n == 0 {
-                                                error!("tunnel {} connection {} read date from {} data length 0",connections_tunnel_name,id,addr);
-                                                write_data.extend_from_slice(&CLOSE_ID.to_be_bytes());
-                                                write_data.extend_from_slice(&id.to_be_bytes());
-                                                let _ = enc_writer.write_to_tunnel(&mut w, &write_data).await;
-                                                break;
-                                            }
-                                            info!("tunnel {} connection {} write {} bytes data to tunnel",connections_tunnel_name,id,n);
-                                            write_data.extend_from_slice(&id.to_be_bytes());
-                                            write_data.extend_from_slice(&buf[..n]);
-                                            let _ = enc_writer.write_to_tunnel(&mut w, &write_data).await;
-                                        },
-                                        Err(e) => {
-                                            error!("tunnel {} connection {} read data from {} error {}",connections_tunnel_name,id,addr,e);
-                                            write_data.extend_from_slice(&CLOSE_ID.to_be_bytes());
-                                            write_data.extend_from_slice(&id.to_be_bytes());
-                                            let _ = enc_writer.write_to_tunnel(&mut w, &write_data).await;
-                                            break;
-                                        }
-                                    }
-                                    drop(w);
-                                }
-    
-                            },
-                            Err(e) => {
-                                let mut w = connections_to_tunnel_writer.lock().await;
-                                write_data.clear();
-                                write_data.extend_from_slice(&CLOSE_ID.to_be_bytes());
-                                write_data.extend_from_slice(&id.to_be_bytes());
-                                let _ = enc_writer.write_to_tunnel(&mut w, &write_data).await;
-                                error!("tunnel {} connection {} connect to {} error {}",connections_tunnel_name,id,addr,e);
-                            }
-                        }
-                    });
-                    handles.push(h);
-                };
+                }
+                None if new_server => {
+                    // 连接已经关闭，丢弃
+                }
+                None => {
+                    // 旧版server没有OPEN，收到第一个数据包时建立连接
+                    // 连接建立前收到的数据先缓存，避免同一个id重复连接
+                    l.insert(id, LocalConn::Connecting(data.to_vec()));
+                    info!(tunnel = tunnel_name.as_str(), connection = id; "tunnel {} new connection {}",tunnel_name,id);
+                    let proxy_header = config.proxy_protocol.map(|v| encode_header(v, None));
+                    let conn_info = ConnInfo { port_offset: 0, proxy_header };
+                    let h = tokio::spawn(local_connection(tunnel_name.clone(), id, conn_info, backends.clone(), tunnel_writer.clone(), connections_writers.clone(), key.clone()));
+                    handles.0.push(h);
+                }
             }
         }
     }
-    for h in handles.iter() {
-        h.abort();
-    }
-    info!("tunnel {} closed",tunnel_name);
+    drop(handles);
+    info!(tunnel = tunnel_name.as_str(); "tunnel {} closed",tunnel_name);
     Ok(())
 }
 
-async fn client(server_addr: SocketAddr, reconn: u64, config: (String, TcpTunnelClientConfig)) {
+async fn upgrade_transport<S: TunnelStream + 'static>(stream: S, client_conf: &ClientConfig) -> tokio::io::Result<BoxedStream> {
+    match client_conf.transport {
+        Transport::Tcp => Ok(Box::new(stream)),
+        Transport::Ws => {
+            let host = client_conf.ws_host.clone().unwrap_or_else(|| client_conf.server_addr.clone());
+            Ok(Box::new(ws_connect(stream, &host, &client_conf.ws_path).await?))
+        }
+    }
+}
+
+// tcp -> tls(可选) -> websocket(可选)
+async fn connect_server(client_conf: &ClientConfig) -> tokio::io::Result<BoxedStream> {
+    // 每次重连都重新解析server_addr，依次尝试所有ipv4和ipv6地址
+    let stream = TcpStream::connect(client_conf.server_addr.as_str()).await?;
+    info!("connected to server {} ({:?})",client_conf.server_addr,stream.peer_addr());
+    #[cfg(feature = "tls")]
+    if let Some(tls_conf) = &client_conf.tls {
+        let connector = tcp_tunnel::tls::client_connector(tls_conf)?;
+        let host = tcp_tunnel::addr_host(&client_conf.server_addr);
+        let server_name = tcp_tunnel::tls::server_name(tls_conf.server_name.as_deref().unwrap_or(host))?;
+        let stream = connector.connect(server_name, stream).await?;
+        return upgrade_transport(stream, client_conf).await;
+    }
+    upgrade_transport(stream, client_conf).await
+}
+
+async fn client(client_conf: Arc<ClientConfig>, config: (String, TcpTunnelClientConfig), backends: Arc<Backends>, shutdown: watch::Receiver<bool>) {
     let tunnel_name = config.0.clone();
-    loop {
-        let s = TcpStream::connect(server_addr).await;
-        match s {
-            Ok(stream) => {
-                let _r = client_handle(stream,config.clone()).await;
+    let server_addr = client_conf.server_addr.clone();
+    let reconnect = async {
+        // server退出前发送GOAWAY后，旧的隧道连接继续传输已有连接，同时重连新的server
+        let mut draining = TaskGroup(vec![]);
+        loop {
+            if *shutdown.borrow() {
+                std::future::pending::<()>().await;
+            }
+            backends.set_state(State::Connecting);
+            let s = connect_server(&client_conf).await;
+            match s {
+                Ok(stream) => {
+                    backends.set_state(State::Authenticating);
+                    let (goaway_tx,goaway_rx) = oneshot::channel();
+                    let handle = client_handle(stream,config.clone(),backends.clone(),goaway_tx,shutdown.clone());
+                    let handshake_tunnel_name = tunnel_name.clone();
+                    let handshake_backends = backends.clone();
+                    // client任务被中止（如重新加载配置）时，guard中止隧道连接任务
+                    let mut session = TaskGroup(vec![tokio::spawn(async move {
+                        if let Err(e) = handle.await {
+                            error!(tunnel = handshake_tunnel_name.as_str(); "tunnel {} handshake error {}",handshake_tunnel_name,e);
+                            handshake_backends.set_error(format!("handshake: {}", e));
+                            let reason = if e.kind() == tokio::io::ErrorKind::PermissionDenied { "auth" } else { "write" };
+                            metrics::HANDSHAKE_FAILURES.inc(&[("reason",reason)]);
+                        }
+                    })]);
+                    let goaway = tokio::select! {
+                        _ = &mut session.0[0] => false,
+                        r = goaway_rx => r.is_ok(),
+                    };
+                    draining.0.retain(|h| !h.is_finished());
+                    if goaway {
+                        draining.0.append(&mut session.0);
+                        continue;
+                    }
+                    // goaway_rx被关闭说明client_handle已经返回，等待任务结束
+                    if !session.0[0].is_finished() {
+                        let _ = (&mut session.0[0]).await;
+                    }
+                },
+                Err(e) => {
+                    error!(tunnel = tunnel_name.as_str(); "tunnel {} connect to {} {}",tunnel_name,server_addr,e);
+                    backends.set_error(format!("connect to {}: {}", server_addr, e));
+                    metrics::HANDSHAKE_FAILURES.inc(&[("reason","connect")]);
+                }
+            }
+            backends.set_state(State::Backoff);
+            tokio::time::sleep(Duration::from_secs(client_conf.reconn)).await;
+            info!(tunnel = tunnel_name.as_str(); "tunnel {} reconnecting to server {}",tunnel_name,server_addr);
+            metrics::RECONNECTS.inc(&[("tunnel",&tunnel_name)]);
+        }
+    };
+    match &config.1.health_check {
+        Some(health_conf) => {
+            tokio::join!(health_check_loop(&tunnel_name, health_conf, &backends), reconnect);
+        }
+        None => reconnect.await,
+    }
+}
 
-            },
-            Err(e) => {
-                error!("tunnel {} connect to {} {}",tunnel_name,server_addr,e);
+fn start_tunnel(client_conf: &Arc<ClientConfig>, name: &str, conf: &TcpTunnelClientConfig, tunnels: &Tunnels, shutdown: &watch::Receiver<bool>) -> tokio::task::JoinHandle<()> {
+    let backends = Arc::new(Backends::new(name, conf));
+    tunnels.lock().unwrap().insert(name.to_string(), backends.clone());
+    tokio::spawn(client(client_conf.clone(), (name.to_string(), conf.clone()), backends, shutdown.clone()))
+}
+
+fn format_uptime(secs: u64) -> String {
+    match secs {
+        0..60 => format!("{}s", secs),
+        60..3600 => format!("{}m{}s", secs / 60, secs % 60),
+        3600..86400 => format!("{}h{}m", secs / 3600, secs % 3600 / 60),
+        _ => format!("{}d{}h", secs / 86400, secs % 86400 / 3600),
+    }
+}
+
+fn status_text(tunnels: &Tunnels) -> String {
+    let mut rows = vec![["TUNNEL","STATE","UPTIME","REMOTE","CONNECTIONS","LAST ERROR"].map(String::from)];
+    let tunnels = tunnels.lock().unwrap();
+    let mut names: Vec<&String> = tunnels.keys().collect();
+    names.sort();
+    for name in names {
+        let backends = &tunnels[name];
+        let state = backends.state();
+        let uptime = if state.state == State::Up { format_uptime(state.since.elapsed().as_secs()) } else { "-".to_string() };
+        rows.push([
+            name.clone(),
+            state.state.as_str().to_string(),
+            uptime,
+            state.bound.clone().unwrap_or_else(|| "-".to_string()),
+            backends.connections().to_string(),
+            state.last_error.clone().unwrap_or_else(|| "-".to_string()),
+        ]);
+    }
+    let mut widths = [0;6];
+    for row in rows.iter() {
+        for (w,col) in widths.iter_mut().zip(row.iter()) {
+            *w = (*w).max(col.len());
+        }
+    }
+    let mut text = String::new();
+    for row in rows.iter() {
+        let line: Vec<String> = row.iter().zip(widths.iter()).map(|(col,w)| format!("{:<w$}", col, w = w)).collect();
+        text.push_str(line.join("  ").trim_end());
+        text.push('\n');
+    }
+    text
+}
+
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(5);
+
+// 只删除上次退出时没有删除的socket文件，不覆盖其他文件和正在使用的socket
+#[cfg(unix)]
+fn bind_control_socket(path: &str) -> Result<tokio::net::UnixListener, String> {
+    use std::os::unix::fs::FileTypeExt;
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => {
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                return Err(format!("control socket {} is in use by another client", path));
             }
+            std::fs::remove_file(path).map_err(|e| format!("unable to remove stale control socket {}: {}", path, e))?;
         }
-        tokio::time::sleep(Duration::from_secs(reconn)).await;
-        info!("tunnel {} reconnecting to server {}",tunnel_name,server_addr);
+        Ok(_) => return Err(format!("control socket {} already exists and is not a socket", path)),
+        Err(_) => {}
+    }
+    tokio::net::UnixListener::bind(path).map_err(|e| format!("unable to listen on control socket {}: {}", path, e))
+}
+
+// 本地控制socket，每个连接读取一行命令，返回结果后关闭
+#[cfg(unix)]
+async fn control_server(listener: tokio::net::UnixListener, path: String, tunnels: Tunnels) {
+    info!("control socket listening on {}", path);
+    while let Ok((stream, _)) = listener.accept().await {
+        let tunnels = tunnels.clone();
+        tokio::spawn(async move {
+            let (reader,mut writer) = stream.into_split();
+            let mut line = String::new();
+            let mut reader = tokio::io::BufReader::new(reader);
+            match tokio::time::timeout(CONTROL_TIMEOUT, reader.read_line(&mut line)).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => {
+                    error!("control socket read command error: {}",e);
+                    return;
+                }
+                Err(_) => {
+                    error!("control socket read command timeout");
+                    return;
+                }
+            }
+            let response = match line.trim() {
+                "status" => status_text(&tunnels),
+                command => format!("unknown command {:?}\n", command),
+            };
+            let _ = writer.write_all(response.as_bytes()).await;
+            let _ = writer.shutdown().await;
+        });
+    }
+}
+
+// client status，连接运行中client的控制socket并打印结果
+#[cfg(unix)]
+async fn status_command(path: &str) -> Result<String, String> {
+    let query = async {
+        let mut stream = tokio::net::UnixStream::connect(path).await?;
+        stream.write_all(b"status\n").await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        Ok::<_, tokio::io::Error>(response)
+    };
+    match tokio::time::timeout(CONTROL_TIMEOUT, query).await {
+        Ok(Ok(response)) => Ok(response),
+        Ok(Err(e)) => Err(format!("unable to connect to control socket {}: {}, is the client running?", path, e)),
+        Err(_) => Err(format!("control socket {} timeout", path)),
     }
 }
 
+#[cfg(not(unix))]
+async fn status_command(_path: &str) -> Result<String, String> {
+    Err("status is not supported on this platform".to_string())
+}
+
+async fn status(cli: &Cli) -> Result<String, String> {
+    let path = match &cli.socket {
+        Some(path) => path.clone(),
+        None => {
+            // 只读取control_socket，不需要key文件、环境变量和证书
+            let config: toml::value::Table = read_config(cli.source()).map_err(|e| e.to_string())?;
+            config.get("control_socket").and_then(|v| v.as_str()).map(|p| p.to_string())
+                .ok_or_else(|| format!("control_socket is not configured in {}", cli.source()))?
+        }
+    };
+    status_command(&path).await
+}
+
+// 重新加载配置，只重启新增、删除或修改了的tunnel；连接server的配置变化时重启所有tunnel
+#[cfg(unix)]
+fn reload(path: &str, running: &ClientConfig, config: &mut Arc<ClientConfig>, handles: &mut HashMap<String, tokio::task::JoinHandle<()>>, tunnels: &Tunnels, shutdown: &watch::Receiver<bool>) {
+    let new_config = match load_client_config(path) {
+        Ok(new_config) => Arc::new(new_config),
+        Err(e) => {
+            error!("reload config error: {}",e);
+            return;
+        }
+    };
+    let restart = running.restart_fields(&new_config);
+    if !restart.is_empty() {
+        warn!("config {} changed {}, restart the client to apply them",path,restart.join(", "));
+    }
+    let same_server = config.same_server(&new_config);
+    handles.retain(|name,h| {
+        let unchanged = same_server && new_config.tunnel.get(name) == config.tunnel.get(name);
+        if !unchanged {
+            info!(tunnel = name.as_str(); "tunnel {} stopped",name);
+            h.abort();
+            tunnels.lock().unwrap().remove(name);
+        }
+        unchanged
+    });
+    for (k,v) in new_config.tunnel.iter() {
+        if !handles.contains_key(k) {
+            info!(tunnel = k.as_str(); "tunnel {} started",k);
+            handles.insert(k.clone(), start_tunnel(&new_config, k, v, tunnels, shutdown));
+        }
+    }
+    *config = new_config;
+    info!("config {} reloaded",path);
+}
+
 #[tokio::main]
 async fn main() {
-    #[cfg(target_arch="x86_64")]
-    env_logger::init();
-    let args:Vec<String> = std::env::args().collect();
-    let config = load_client_config(&args[1]);
-    let mut handles = vec![];
+    let cli = Cli::from_env(true);
+    if cli.status {
+        match status(&cli).await {
+            Ok(text) => print!("{}", text),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    #[allow(unused_mut)]
+    let mut config = match cli.load_client_config() {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    // --check只检查配置，不启动
+    if cli.check {
+        println!("{}: ok", cli.source());
+        return;
+    }
+    cli.init_logger(&config.log);
+    if let Some(addr) = config.metrics_listen {
+        tokio::spawn(metrics::serve(listen_or_exit("metrics server", addr)));
+    }
+    let (shutdown_tx,shutdown_rx) = watch::channel(false);
+    let tunnels: Tunnels = Arc::new(std::sync::Mutex::new(HashMap::new()));
+    // 重新加载配置时不改变
+    let control_socket = config.control_socket.clone();
+    #[cfg(unix)]
+    if let Some(path) = control_socket.clone() {
+        let listener = match bind_control_socket(&path) {
+            Ok(listener) => listener,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        };
+        tokio::spawn(control_server(listener, path, tunnels.clone()));
+    }
+    #[allow(unused_mut)]
+    let mut handles = HashMap::new();
     for (k,v) in config.tunnel.iter() {
-        let addr = config.server_addr;
-        handles.push(tokio::spawn(client(addr, config.reconn, (k.clone(),v.clone()))));
+        handles.insert(k.clone(), start_tunnel(&config, k, v, &tunnels, &shutdown_rx));
+    }
+    #[cfg(unix)]
+    let reload_loop = async {
+        // 只有使用配置文件时才能重新加载
+        let Some(path) = cli.config.clone() else {
+            return std::future::pending::<()>().await;
+        };
+        let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()).expect("Unable to listen for SIGHUP");
+        // 启动时的配置，metrics_listen等不能重新加载
+        let running = config.clone();
+        while hangup.recv().await.is_some() {
+            info!("received SIGHUP, reloading config {}",path);
+            reload(&path, &running, &mut config, &mut handles, &tunnels, &shutdown_rx);
+        }
+    };
+    #[cfg(not(unix))]
+    let reload_loop = std::future::pending::<()>();
+    tokio::select! {
+        _ = reload_loop => {},
+        _ = shutdown_signal() => {},
+    }
+
+    // 上报server不再分配新连接，等待已有连接结束或超时
+    info!("shutting down, waiting for connections to finish");
+    let _ = shutdown_tx.send(true);
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(config.drain_timeout);
+    loop {
+        let active = ACTIVE_CONNECTIONS.load(Ordering::Relaxed);
+        if active == 0 {
+            info!("all connections finished");
+            break;
+        }
+        if tokio::time::Instant::now() >= deadline {
+            info!("drain timeout, closing {} connections",active);
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
     }
-    for h in handles {
-        let _ = h.await;
+    if let Some(path) = control_socket {
+        let _ = std::fs::remove_file(path);
     }
 }
@@ -1,149 +1,596 @@
-use std::{collections::HashMap, net::{Ipv4Addr, SocketAddr, SocketAddrV4}, sync::Arc, time::Duration};
-use tcp_tunnel::{load_server_config, xor, EncReader, EncWriter, TcpTunnelServerConfig, CLOSE_ID, CONNECTION_ID_START, PING_ID};
-use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{TcpListener, TcpStream}, sync::Mutex};
-use log::{info, error, debug};
+use std::{collections::HashMap, net::{IpAddr, SocketAddr}, sync::{atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering}, Arc, OnceLock}, time::{Duration, Instant}};
+use tcp_tunnel::{balance::{balance_order, Balance}, bind_listener, cli::Cli, http::{base64_encode, http_header, json_string, read_http_head, rewrite_request}, listen_or_exit, load_server_config, logging::{self, AccessRecord}, metrics::{self, GaugeGuard}, normalize_fingerprint, ping::{self, RttStats, PING_INTERVAL, PING_LEN}, shutdown_signal, port_range, proxy_protocol, sni::read_client_hello, ws::ws_accept, xor, BoxedStream, EncReader, EncWriter, Handshake, PrefixedStream, ServerConfig, TcpTunnelServerConfig, TunnelStream, TunnelWriter, BOUND_ID, CLOSE_ID, GOAWAY_ID, CONNECTION_ID_START, HANDSHAKE_END, HEALTH_ID, OPEN_ID, PING_ID, PONG_ID};
+use tokio::{io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt}, net::{tcp::OwnedWriteHalf, TcpListener, TcpStream}, sync::{Mutex, Notify, RwLock}, task::JoinHandle};
+use log::{info, error, debug, warn};
 
-async fn server_handle(mut tunnel_stream: TcpStream, tunnel_confs:Arc<HashMap<String,TcpTunnelServerConfig>>) {
-    let mut buf = [0u8; 1024];
-    // 读取客户端发来的认证信息并进行验证
-    let n = match tunnel_stream.read(&mut buf).await {
-        Ok(0) => {
-            error!("tunnel client closed the connection");
-            return;
+#[cfg(feature = "tls")]
+use tokio_rustls::TlsAcceptor;
+
+#[cfg(not(feature = "tls"))]
+#[derive(Clone)]
+struct TlsAcceptor;
+
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_HANDSHAKE_LEN: usize = 64 * 1024;
+// 旧版client的认证信息没有结束标记，这段时间内没有更多数据才认为读完
+const V1_HANDSHAKE_WAIT: Duration = Duration::from_millis(500);
+const HTTP_HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_NOT_FOUND: &str = "<html><body><h1>404 Not Found</h1></body></html>";
+const UNAUTHORIZED: &str = "<html><body><h1>401 Unauthorized</h1></body></html>";
+const HTML: &str = "text/html; charset=utf-8";
+const JSON: &str = "application/json";
+
+#[derive(Clone,Copy,PartialEq)]
+enum ListenerKind {
+    // listen_port，自动识别tcp、tls和websocket
+    Auto,
+    Ws,
+    Tls,
+}
+
+async fn read_prefix<S: AsyncRead + Unpin>(stream: &mut S) -> tokio::io::Result<Vec<u8>> {
+    let mut buf = vec![0u8;1024];
+    let mut n = 0;
+    while n < 4 {
+        let r = stream.read(&mut buf[n..]).await?;
+        if r == 0 {
+            return Err(tokio::io::Error::new(tokio::io::ErrorKind::UnexpectedEof, "connection closed before handshake"));
         }
-        Ok(n) => n,
-        Err(e) => {
-            error!("failed to read from tunnel stream: {}", e);
+        n += r;
+    }
+    buf.truncate(n);
+    Ok(buf)
+}
+
+async fn accept_ws_or_raw<S: TunnelStream + 'static>(stream: S, prefix: Vec<u8>) -> tokio::io::Result<BoxedStream> {
+    let is_ws = prefix.starts_with(b"GET ");
+    let stream = PrefixedStream::new(prefix, stream);
+    if is_ws {
+        Ok(Box::new(ws_accept(stream).await?))
+    } else {
+        Ok(Box::new(stream))
+    }
+}
+
+// 返回隧道流和tls客户端证书指纹
+async fn accept_transport<S: TunnelStream + 'static>(mut stream: S, kind: ListenerKind, tls: Option<TlsAcceptor>) -> tokio::io::Result<(BoxedStream,Option<String>)> {
+    if kind == ListenerKind::Ws {
+        return Ok((Box::new(ws_accept(stream).await?), None));
+    }
+    let prefix = read_prefix(&mut stream).await?;
+    #[cfg(feature = "tls")]
+    if let Some(acceptor) = tls {
+        if kind == ListenerKind::Tls || prefix.starts_with(&[0x16, 0x03]) {
+            let mut tls_stream = acceptor.accept(PrefixedStream::new(prefix, stream)).await?;
+            let fingerprint = tcp_tunnel::tls::peer_fingerprint(&tls_stream);
+            let prefix = read_prefix(&mut tls_stream).await?;
+            return Ok((accept_ws_or_raw(tls_stream, prefix).await?, fingerprint));
+        }
+    }
+    #[cfg(not(feature = "tls"))]
+    let _ = tls;
+    Ok((accept_ws_or_raw(stream, prefix).await?, None))
+}
+
+// 一个公网连接，供管理接口查询和关闭
+struct Connection {
+    src: SocketAddr,
+    dst: SocketAddr,
+    started: Instant,
+    // 公网发往client的字节数
+    bytes_in: AtomicU64,
+    // client发往公网的字节数
+    bytes_out: AtomicU64,
+    close: Notify,
+    // 访问日志中的关闭原因，以最先出现的为准
+    reason: OnceLock<String>,
+}
+
+impl Connection {
+    fn set_reason(&self, reason: impl Into<String>) {
+        let _ = self.reason.set(reason.into());
+    }
+}
+
+// 连接结束时写访问日志，任务被中止时也能记录
+struct AccessGuard {
+    tunnel_name: String,
+    id: u32,
+    conn: Arc<Connection>,
+}
+
+impl Drop for AccessGuard {
+    fn drop(&mut self) {
+        let conn = &self.conn;
+        logging::access(&AccessRecord {
+            tunnel: &self.tunnel_name,
+            id: self.id,
+            src: conn.src,
+            dst: conn.dst,
+            started: conn.started,
+            bytes_in: conn.bytes_in.load(Ordering::Relaxed),
+            bytes_out: conn.bytes_out.load(Ordering::Relaxed),
+            // client断开时任务被中止
+            reason: conn.reason.get().map(|r| r.as_str()).unwrap_or("tunnel closed"),
+        });
+    }
+}
+
+// 一个client的隧道连接
+struct Session {
+    client_addr: SocketAddr,
+    connected: Instant,
+    // client协议版本，旧版client不发送OPEN
+    version: u32,
+    // client上报的后端健康状态
+    healthy: AtomicBool,
+    tunnel_writer: Arc<Mutex<TunnelWriter>>,
+    client_writers: Arc<Mutex<HashMap<u32, OwnedWriteHalf>>>,
+    handles: Mutex<Vec<JoinHandle<()>>>,
+    conns: Mutex<HashMap<u32, Arc<Connection>>>,
+    rtt: RttStats,
+    // 重新加载配置或管理接口断开client
+    kick: Notify,
+}
+
+// 一个监听端口，由一个或多个同名tunnel的client共享
+struct Tunnel {
+    name: String,
+    key: String,
+    // client请求的remote_addr
+    remote_addr: String,
+    // 实际监听的地址，端口范围时有多个，只注册域名时没有监听端口
+    listen_addrs: Vec<SocketAddr>,
+    domains: Vec<String>,
+    // client要求替换的Host头
+    host_rewrite: Option<String>,
+    // http basic认证，Authorization头的期望值
+    http_auth: Option<String>,
+    balance: Balance,
+    accept_proxy_protocol: bool,
+    sessions: Mutex<Vec<Arc<Session>>>,
+    next_session: AtomicUsize,
+    next_conn_id: AtomicU32,
+    listener_handles: Mutex<Vec<JoinHandle<()>>>,
+    // prometheus指标
+    // 上报后端健康的client数
+    healthy_clients: Arc<AtomicU64>,
+    active_conns: Arc<AtomicU64>,
+    bytes_in: Arc<AtomicU64>,
+    bytes_out: Arc<AtomicU64>,
+}
+
+impl Tunnel {
+    // 端口范围显示为"ip:start-end"
+    fn bound_addr(&self) -> String {
+        match (self.listen_addrs.first(),self.listen_addrs.last()) {
+            (Some(first),Some(last)) if first != last => format!("{}-{}", first, last.port()),
+            (Some(first),_) => first.to_string(),
+            _ => String::new(),
+        }
+    }
+
+    // 只在后端健康的client中选择
+    async fn pick_session(&self) -> Option<Arc<Session>> {
+        let sessions = self.sessions.lock().await;
+        let sessions: Vec<&Arc<Session>> = sessions.iter().filter(|s| s.healthy.load(Ordering::Relaxed)).collect();
+        let mut loads = vec![];
+        for s in sessions.iter() {
+            loads.push(s.client_writers.lock().await.len());
+        }
+        balance_order(self.balance, &self.next_session, &loads)
+            .first()
+            .map(|i| (*sessions[*i]).clone())
+    }
+}
+
+type Tunnels = Arc<Mutex<HashMap<String, Arc<Tunnel>>>>;
+// 可以重新加载的tunnel配置
+type TunnelConfs = Arc<RwLock<HashMap<String, TcpTunnelServerConfig>>>;
+
+// 精确匹配优先，其次匹配"*.example.com"
+async fn find_tunnel_by_host(tunnels: &Tunnels, host: &str) -> Option<Arc<Tunnel>> {
+    let tunnels = tunnels.lock().await;
+    tunnels.values().find(|t| t.domains.iter().any(|d| d == host))
+        .or_else(|| tunnels.values().find(|t| t.domains.iter().any(|d| d.strip_prefix('*').is_some_and(|suffix| suffix.starts_with('.') && host.ends_with(suffix)))))
+        .cloned()
+}
+
+// 接受公网连接，每新建一个连接，按负载均衡选择一个client，创建一个task，共用该client的tunnel_writer
+async fn tunnel_accept(tunnel: Arc<Tunnel>, listen_stream: TcpListener) {
+    while let Ok((stream,addr)) = listen_stream.accept().await {
+        let local_addr = match stream.local_addr() {
+            Ok(local_addr) => local_addr,
+            Err(_) => continue,
+        };
+        if !tunnel.accept_proxy_protocol {
+            dispatch_connection(tunnel.clone(), stream, addr, local_addr, vec![]).await;
+            continue;
+        }
+        // 前面有负载均衡时，从PROXY protocol头中获取真实地址
+        let tunnel = tunnel.clone();
+        tokio::spawn(async move {
+            let mut stream = stream;
+            match tokio::time::timeout(PROXY_HEADER_TIMEOUT, proxy_protocol::read_header(&mut stream)).await {
+                Ok(Ok((addrs,initial_data))) => {
+                    let (src,dst) = addrs.unwrap_or((addr,local_addr));
+                    dispatch_connection(tunnel, stream, src, dst, initial_data).await;
+                }
+                Ok(Err(e)) => {
+                    error!(tunnel = tunnel.name.as_str(); "tunnel {} connection from {} proxy protocol error: {}",tunnel.name,addr,e);
+                    metrics::CONNECTIONS.inc(&[("tunnel",&tunnel.name),("result","failed")]);
+                }
+                Err(_) => {
+                    error!(tunnel = tunnel.name.as_str(); "tunnel {} connection from {} proxy protocol header timeout",tunnel.name,addr);
+                    metrics::CONNECTIONS.inc(&[("tunnel",&tunnel.name),("result","failed")]);
+                }
+            }
+        });
+    }
+}
+
+// 按负载均衡选择一个client，创建一个task，共用该client的tunnel_writer；initial_data为已经从连接中读出的数据
+async fn dispatch_connection(tunnel: Arc<Tunnel>, stream: TcpStream, addr: SocketAddr, local_addr: SocketAddr, initial_data: Vec<u8>) {
+    let session = match tunnel.pick_session().await {
+        Some(session) => session,
+        None => {
+            error!(tunnel = tunnel.name.as_str(); "tunnel {} has no healthy client, connection from {} refused",tunnel.name,addr);
+            metrics::CONNECTIONS.inc(&[("tunnel",&tunnel.name),("result","refused")]);
             return;
         }
     };
-    let buffer = &buf[..n];
-    debug!("auth data {:?}",buffer);
-    let len = buffer[0] as usize;
-    if len + 1 > buffer.len() {
-        error!("unknown tunnel connection");
-        return;
+    let id = tunnel.next_conn_id.fetch_add(1, Ordering::Relaxed);
+    info!(tunnel = tunnel.name.as_str(), connection = id; "tunnel {} connection {} from {} dispatched to client {}",tunnel.name,id,addr,session.client_addr);
+    // 使用PROXY protocol时local_addr是负载均衡的目标地址，端口范围中的位置按实际监听端口计算
+    let port_offset = stream.local_addr().ok().and_then(|a| tunnel.listen_addrs.iter().position(|l| l.port() == a.port())).unwrap_or(0);
+    let (mut reader,writer) = stream.into_split();
+    // 发送OPEN前先存入writer，后端先发数据时（如ssh banner）也能找到公网连接
+    let mut l = session.client_writers.lock().await;
+    l.insert(id, writer);
+    drop(l);
+    let conn = Arc::new(Connection {
+        src: addr,
+        dst: local_addr,
+        started: Instant::now(),
+        bytes_in: AtomicU64::new(initial_data.len() as u64),
+        bytes_out: AtomicU64::new(0),
+        close: Notify::new(),
+        reason: OnceLock::new(),
+    });
+    session.conns.lock().await.insert(id, conn.clone());
+    let mut enc_writer = EncWriter::new(tunnel.key.clone());
+    let mut write_data = vec![];
+    if session.version >= 2 {
+        // 先通知client建立连接，client可以立即连接后端并发送PROXY protocol头
+        write_data.extend_from_slice(&OPEN_ID.to_be_bytes());
+        write_data.extend_from_slice(&id.to_be_bytes());
+        write_data.extend_from_slice(format!("{} {} {}", addr, local_addr, port_offset).as_bytes());
+        let mut w = session.tunnel_writer.lock().await;
+        if let Err(e) = enc_writer.write_to_tunnel(&mut *w, &write_data).await {
+            drop(w);
+            error!(tunnel = tunnel.name.as_str(), connection = id; "tunnel {} connection {} send open to client {} error: {}",tunnel.name,id,session.client_addr,e);
+            metrics::CONNECTIONS.inc(&[("tunnel",&tunnel.name),("result","failed")]);
+            session.conns.lock().await.remove(&id);
+            session.client_writers.lock().await.remove(&id);
+            return;
+        }
     }
+    let conn_session = session.clone();
+    let writer = session.tunnel_writer.clone();
+    let tunnel_name = tunnel.name.clone();
+    metrics::CONNECTIONS.inc(&[("tunnel",&tunnel.name),("result","accepted")]);
+    tunnel.bytes_in.fetch_add(initial_data.len() as u64, Ordering::Relaxed);
+    let bytes_in = tunnel.bytes_in.clone();
+    let active_guard = GaugeGuard::new(tunnel.active_conns.clone());
+    let access_guard = AccessGuard { tunnel_name: tunnel.name.clone(), id, conn: conn.clone() };
+    let h = tokio::spawn(async move {
+        let _active_guard = active_guard;
+        let _access_guard = access_guard;
+        let mut buf = [0;4096];
+        let mut closed = false;
 
-    let tunnel_name = String::from_utf8_lossy(&buffer[1..1+len]);
-    let tunnel_name:&str = &tunnel_name;
+        if !initial_data.is_empty() {
+            write_data.clear();
+            write_data.extend_from_slice(&id.to_be_bytes());
+            write_data.extend_from_slice(&initial_data);
+            let mut w = writer.lock().await;
+            let _ = enc_writer.write_to_tunnel(&mut *w, &write_data).await;
+        }
 
-    let conf = tunnel_confs.get(tunnel_name);
-    if conf.is_none() {
-        error!("not found tunnel config {:?}",tunnel_name);
-        return;
-    }
-    
-    let conf = conf.unwrap().clone();
-    let mut output = vec![];
+        loop {
+            let r = tokio::select! {
+                r = reader.read(&mut buf) => r,
+                _ = conn.close.notified() => {
+                    closed = true;
+                    Err(tokio::io::Error::other("closed by admin"))
+                }
+            };
+            let mut w = writer.lock().await;
+            write_data.clear();
+            match r {
+                Ok(n) => {
+                    debug!(tunnel = tunnel_name.as_str(), connection = id; "tunnel {} connection {} read data from client {}",tunnel_name,id,String::from_utf8_lossy(&buf[..n]));
+                    if n == 0 {
+                        error!(tunnel = tunnel_name.as_str(), connection = id; "tunnel {} connection {} read data 0, send close to tunnel",tunnel_name,id);
+                        conn.set_reason("remote closed");
+                        write_data.extend_from_slice(&CLOSE_ID.to_be_bytes());
+                        write_data.extend_from_slice(&id.to_be_bytes());
+                        let _ = enc_writer.write_to_tunnel(&mut *w, &write_data).await;
+                        break;
+                    }
+                    conn.bytes_in.fetch_add(n as u64, Ordering::Relaxed);
+                    bytes_in.fetch_add(n as u64, Ordering::Relaxed);
+                    write_data.extend_from_slice(&id.to_be_bytes());
+                    write_data.extend_from_slice(&buf[..n]);
+                    let r = enc_writer.write_to_tunnel(&mut *w, &write_data).await;
+                    if let Err(e) = &r {
+                        error!(tunnel = tunnel_name.as_str(), connection = id; "tunnel {} connection {} read data written to tunnel error: {:?}",tunnel_name,id,r);
+                        conn.set_reason(format!("tunnel write error: {}", e));
+                        break;
+                    }
+                    info!(tunnel = tunnel_name.as_str(), connection = id; "tunnel {} connection {} read data written to tunnel ok",tunnel_name,id);
+                },
+                Err(e) => {
+                    error!(tunnel = tunnel_name.as_str(), connection = id; "tunnel {} connection {} error while read from client {}: {}",tunnel_name,id,addr,e);
+                    conn.set_reason(if closed { e.to_string() } else { format!("read error: {}", e) });
+                    write_data.extend_from_slice(&CLOSE_ID.to_be_bytes());
+                    write_data.extend_from_slice(&id.to_be_bytes());
+                    let _ = enc_writer.write_to_tunnel(&mut *w, &write_data).await;
+                    break;
+                }
+            }
+        }
+        conn_session.conns.lock().await.remove(&id);
+        // 管理接口关闭时公网连接的写端也要关闭
+        if closed {
+            if let Some(mut s) = conn_session.client_writers.lock().await.remove(&id) {
+                let _ = s.shutdown().await;
+            }
+        }
+        info!(tunnel = tunnel_name.as_str(), connection = id; "tunnel {} connection {} finished",tunnel_name,id);
+    });
+    let mut handles = session.handles.lock().await;
+    handles.retain(|h| !h.is_finished());
+    handles.push(h);
+}
 
-    xor(&buffer[1+len..], conf.key.as_bytes(), &mut output);
-    
-    let addr = String::from_utf8_lossy(&output);
+// 从allow_ports中随机选择一个可用端口，没有配置时由系统分配
+fn bind_auto_port(ip: IpAddr, allow_ports: &[u16]) -> tokio::io::Result<TcpListener> {
+    if allow_ports.is_empty() {
+        return bind_listener(SocketAddr::new(ip, 0));
+    }
+    let start = rand::random_range(0..allow_ports.len());
+    let mut last_err = tokio::io::Error::new(tokio::io::ErrorKind::AddrInUse, "no free port in allow_ports");
+    for i in 0..allow_ports.len() {
+        match bind_listener(SocketAddr::new(ip, allow_ports[(start + i) % allow_ports.len()])) {
+            Ok(listener) => return Ok(listener),
+            Err(e) => last_err = e,
+        }
+    }
+    Err(last_err)
+}
 
-    let listen_addr = addr.parse::<SocketAddr>();
-    if listen_addr.is_err() {
-        error!("tunnel {} unknown listen addr {:?}",tunnel_name,addr);
-        return;
+// 认证信息可能分多次到达，整个认证过程不超过HANDSHAKE_TIMEOUT
+async fn read_handshake(stream: &mut BoxedStream, buffer: &mut Vec<u8>, deadline: tokio::time::Instant) -> tokio::io::Result<()> {
+    if buffer.len() > MAX_HANDSHAKE_LEN {
+        return Err(tokio::io::Error::new(tokio::io::ErrorKind::InvalidData, "handshake too large"));
     }
-    
+    let mut buf = [0u8; 1024];
+    let n = tokio::time::timeout_at(deadline, stream.read(&mut buf)).await
+        .map_err(|_| tokio::io::Error::new(tokio::io::ErrorKind::TimedOut, "handshake timeout"))??;
+    if n == 0 {
+        return Err(tokio::io::Error::new(tokio::io::ErrorKind::UnexpectedEof, "tunnel client closed the connection"));
+    }
+    buffer.extend_from_slice(&buf[..n]);
+    Ok(())
+}
 
-    let listen_addr = listen_addr.unwrap();
+async fn server_handle(mut tunnel_stream: BoxedStream, client_addr: SocketAddr, peer_fingerprint: Option<String>, deadline: tokio::time::Instant, tunnel_confs: TunnelConfs, allow_ports: Arc<Vec<u16>>, tunnels: Tunnels) {
+    let handshake_failed = |e: tokio::io::Error| {
+        let reason = match e.kind() {
+            tokio::io::ErrorKind::UnexpectedEof => "closed",
+            tokio::io::ErrorKind::TimedOut => "timeout",
+            tokio::io::ErrorKind::InvalidData => "invalid",
+            _ => "read",
+        };
+        error!("failed to read handshake from {}: {}",client_addr,e);
+        metrics::HANDSHAKE_FAILURES.inc(&[("reason",reason)]);
+    };
+    // 读取客户端发来的认证信息并进行验证，先读取完整的tunnel名称
+    let mut buffer = vec![];
+    while buffer.first().is_none_or(|len| buffer.len() < 1 + *len as usize) {
+        if let Err(e) = read_handshake(&mut tunnel_stream, &mut buffer, deadline).await {
+            handshake_failed(e);
+            return;
+        }
+    }
+    debug!("auth data {:?}",buffer);
+    let len = buffer[0] as usize;
 
-    info!("tunnel {} authentication succeeded",tunnel_name);
+    let tunnel_name = String::from_utf8_lossy(&buffer[1..1+len]).to_string();
+    let tunnel_name:&str = &tunnel_name;
 
-    let r = TcpListener::bind(listen_addr).await;
-    if r.is_err() {
-        error!("tunnel {} error listen at addr {:?}: {:?}",tunnel_name,addr,r);
+    let conf = tunnel_confs.read().await.get(tunnel_name).cloned();
+    if conf.is_none() {
+        error!(tunnel = tunnel_name; "not found tunnel config {:?}",tunnel_name);
+        metrics::HANDSHAKE_FAILURES.inc(&[("reason","unknown_tunnel")]);
         return;
     }
-    let listen_stream = r.unwrap();
-    info!("tunnel {} service listening on address: {}", tunnel_name, listen_addr);
+    
+    let conf = conf.unwrap();
+    if let Some(expected) = &conf.client_fingerprint {
+        if peer_fingerprint.as_deref() != Some(normalize_fingerprint(expected).as_str()) {
+            error!(tunnel = tunnel_name; "tunnel {} client certificate fingerprint mismatch: {:?}",tunnel_name,peer_fingerprint);
+            metrics::HANDSHAKE_FAILURES.inc(&[("reason","fingerprint")]);
+            return;
+        }
+    }
+    let mut output = vec![];
+    // 新版client的认证信息以空行结束，之后可能紧跟着数据帧；旧版client只有一行remote_addr，没有结束标记
+    let (handshake,leftover) = loop {
+        output.clear();
+        xor(&buffer[1+len..], conf.key.as_bytes(), &mut output);
+        if let Some(pos) = output.windows(2).position(|w| w == HANDSHAKE_END) {
+            break (Handshake::parse(&String::from_utf8_lossy(&output[..pos])), buffer[1+len+pos+2..].to_vec());
+        }
+        // 新版client的认证信息也可能在第一个换行前被拆开，不能立即按旧版处理
+        let legacy = !output.is_empty() && !output.contains(&b'\n');
+        let wait = if legacy { deadline.min(tokio::time::Instant::now() + V1_HANDSHAKE_WAIT) } else { deadline };
+        match read_handshake(&mut tunnel_stream, &mut buffer, wait).await {
+            Ok(()) => {}
+            Err(e) if legacy && wait < deadline && e.kind() == tokio::io::ErrorKind::TimedOut => {
+                break (Handshake::parse(&String::from_utf8_lossy(&output)), vec![]);
+            }
+            Err(e) => {
+                handshake_failed(e);
+                return;
+            }
+        }
+    };
+    let tunnel_stream: BoxedStream = Box::new(PrefixedStream::new(leftover, tunnel_stream));
+    let addr = &handshake.remote_addr;
 
+    let mut domains: Vec<String> = handshake.option("domains").unwrap_or("")
+        .split(',')
+        .map(|d| d.trim().to_lowercase())
+        .filter(|d| !d.is_empty())
+        .collect();
+    domains.sort();
+    let listen_addrs = if addr.is_empty() && !domains.is_empty() {
+        vec![]
+    } else {
+        // 端口0只能单独使用，由server分配
+        let addrs: Option<Vec<SocketAddr>> = port_range(addr).filter(|(_,start,end)| *start != 0 || *end == 0).and_then(|(host,start,end)| {
+            (start..=end).map(|port| format!("{}:{}", host, port).parse().ok()).collect()
+        });
+        match addrs {
+            Some(addrs) => addrs,
+            None => {
+                error!(tunnel = tunnel_name; "tunnel {} unknown listen addr {:?}",tunnel_name,addr);
+                metrics::HANDSHAKE_FAILURES.inc(&[("reason","invalid_addr")]);
+                return;
+            }
+        }
+    };
 
+    info!(tunnel = tunnel_name; "tunnel {} authentication succeeded",tunnel_name);
 
-    let (mut tunnel_reader,tunnel_writer) = tunnel_stream.into_split();
+    let (mut tunnel_reader,tunnel_writer) = tokio::io::split(tunnel_stream);
 
     // 多个客户端连接会公用一个tunnel_writer，每个客户端连接单独启动一个任务，当从客户端读取到数据时，向tunnel写入数据。
-    let tunnel_writer = Arc::new(Mutex::new(tunnel_writer));
     // tunnel 需要能获取到客户端连接，当从tunnel读取到数据时，根据连接id向客户端发送数据。
-    let client_writers: Arc<Mutex<HashMap<u32, tokio::net::tcp::OwnedWriteHalf>>> = Arc::new(Mutex::new(HashMap::new()));
-    
-    let client_writers_table = client_writers.clone();
+    let session = Arc::new(Session {
+        client_addr,
+        connected: Instant::now(),
+        version: handshake.version(),
+        healthy: AtomicBool::new(true),
+        tunnel_writer: Arc::new(Mutex::new(tunnel_writer)),
+        client_writers: Arc::new(Mutex::new(HashMap::new())),
+        handles: Mutex::new(vec![]),
+        conns: Mutex::new(HashMap::new()),
+        rtt: RttStats::default(),
+        kick: Notify::new(),
+    });
     let tunnel_name = tunnel_name.to_string();
+    // 端口为0时每个client单独分配端口，不与同名tunnel共用，多台设备可以使用同一份配置模板
+    let key = if listen_addrs.iter().any(|a| a.port() == 0) { format!("{}@{}", tunnel_name, client_addr) } else { tunnel_name.clone() };
 
-    let auth_key = conf.key.clone();
-
-    let connections_to_tunnel_writer = tunnel_writer.clone();
-    let connections_to_tunnel_key = auth_key.clone();
-    let connections_to_tunnel_tname = tunnel_name.clone();
-
-    // 启动一个任务，用于接受客户端的连接，每新建一个连接，创建一个task，共用一个tunnel_writer
-
-    let handles = Arc::new(Mutex::new(vec![]));
-    let shared_handles = handles.clone();
-    let connections_to_tunnel_h = tokio::spawn(async move {
-        let mut id = CONNECTION_ID_START - 1;
-        while let Ok((stream,addr)) = listen_stream.accept().await {
-            id += 1;
-            let (mut reader,writer) = stream.into_split();
-            // 将writer存入client_writers中
-            let mut l: tokio::sync::MutexGuard<'_, HashMap<u32, tokio::net::tcp::OwnedWriteHalf>> = client_writers_table.lock().await;
-            l.insert(id, writer);
-            let writer = connections_to_tunnel_writer.clone();
-            let key = connections_to_tunnel_key.clone();
-            let tunnel_name = connections_to_tunnel_tname.clone();
-            let h = tokio::spawn(async move {
-                let mut buf = [0;4096];
-                
-                let mut write_data = vec![];
-                let mut enc_writer = EncWriter::new(key);
-
-                loop {
-                    let r = reader.read(&mut buf).await;
-                    let mut w = writer.lock().await;
-                    write_data.clear();
+    // 同名tunnel的多个client共用一个监听端口
+    let tunnel = {
+        let mut tunnels_l = tunnels.lock().await;
+        match tunnels_l.get(&key) {
+            Some(tunnel) => {
+                if tunnel.remote_addr != *addr || tunnel.domains != domains {
+                    error!(tunnel = tunnel_name.as_str(); "tunnel {} already registered {:?} {:?}, client {} requested {:?} {:?}",tunnel_name,addr,tunnel.domains,client_addr,addr,domains);
+                    metrics::HANDSHAKE_FAILURES.inc(&[("reason","conflict")]);
+                    return;
+                }
+                let mut sessions = tunnel.sessions.lock().await;
+                sessions.push(session.clone());
+                info!(tunnel = tunnel_name.as_str(); "tunnel {} client {} joined, {} clients connected",tunnel_name,client_addr,sessions.len());
+                tunnel.clone()
+            }
+            None => {
+                if let Some(other) = tunnels_l.values().find(|t| t.domains.iter().any(|d| domains.contains(d))) {
+                    error!(tunnel = tunnel_name.as_str(); "tunnel {} domains {:?} already registered by tunnel {}",tunnel_name,domains,other.name);
+                    metrics::HANDSHAKE_FAILURES.inc(&[("reason","conflict")]);
+                    return;
+                }
+                // 端口范围中任意一个端口监听失败则全部关闭
+                let mut listen_streams = vec![];
+                for listen_addr in listen_addrs.iter() {
+                    // 端口为0时由server分配
+                    let r = if listen_addr.port() == 0 {
+                        bind_auto_port(listen_addr.ip(), &allow_ports)
+                    } else {
+                        bind_listener(*listen_addr)
+                    };
                     match r {
-                        Ok(n) => {
-                            debug!("tunnel {} connection {} read data from client {}",tunnel_name,id,String::from_utf8_lossy(&buf[..n]));
-                            if n == 0 {
-                                error!("tunnel {} connection {} read data 0, send close to tunnel",tunnel_name,id);
-                                write_data.extend_from_slice(&CLOSE_ID.to_be_bytes());
-                                write_data.extend_from_slice(&id.to_be_bytes());
-                                let r = enc_writer.write_to_tunnel(&mut w, &write_data).await;
-                                break;
-                            }
-                            write_data.extend_from_slice(&id.to_be_bytes());
-                            write_data.extend_from_slice(&buf[..n]);
-                            let r = enc_writer.write_to_tunnel(&mut w, &write_data).await;
-                            if r.is_err() {
-                                error!("tunnel {} connection {} read data written to tunnel error: {:?}",tunnel_name,id,r);
-                                break;
-                            }
-                            info!("tunnel {} connection {} read data written to tunnel ok",tunnel_name,id);
-                        },
+                        Ok(listen_stream) => listen_streams.push(listen_stream),
                         Err(e) => {
-                            error!("tunnel {} connection {} error while read from client {}: {}",tunnel_name,id,addr,e);
-                            write_data.extend_from_slice(&CLOSE_ID.to_be_bytes());
-                            write_data.extend_from_slice(&id.to_be_bytes());
-                            let r = enc_writer.write_to_tunnel(&mut w, &write_data).await;
-                            break;
+                            error!(tunnel = tunnel_name.as_str(); "tunnel {} error listen at addr {}: {:?}",tunnel_name,listen_addr,e);
+                            metrics::HANDSHAKE_FAILURES.inc(&[("reason","bind")]);
+                            return;
                         }
                     }
                 }
-                info!("tunnel {} connection {} finished",tunnel_name,id);
-            });
-            let mut handles = shared_handles.lock().await;
-            handles.push(h);
+                let listen_addrs: Vec<SocketAddr> = listen_streams.iter().filter_map(|l| l.local_addr().ok()).collect();
+                if !domains.is_empty() {
+                    info!(tunnel = tunnel_name.as_str(); "tunnel {} registered domains {:?}",tunnel_name,domains);
+                }
+                let tunnel = Arc::new(Tunnel {
+                    name: tunnel_name.clone(),
+                    key: conf.key.clone(),
+                    remote_addr: addr.clone(),
+                    listen_addrs,
+                    domains: domains.clone(),
+                    host_rewrite: handshake.option("host_rewrite").map(|h| h.to_string()),
+                    http_auth: conf.http_user.as_ref().map(|user| format!("Basic {}", base64_encode(format!("{}:{}", user, conf.http_password.as_deref().unwrap_or("")).as_bytes()))),
+                    balance: conf.balance,
+                    accept_proxy_protocol: conf.accept_proxy_protocol,
+                    sessions: Mutex::new(vec![session.clone()]),
+                    next_session: AtomicUsize::new(0),
+                    next_conn_id: AtomicU32::new(CONNECTION_ID_START),
+                    listener_handles: Mutex::new(vec![]),
+                    healthy_clients: metrics::BACKEND_HEALTHY.with(&[("tunnel",&tunnel_name)]),
+                    active_conns: metrics::ACTIVE_CONNECTIONS.with(&[("tunnel",&tunnel_name)]),
+                    bytes_in: metrics::BYTES_IN.with(&[("tunnel",&tunnel_name)]),
+                    bytes_out: metrics::BYTES_OUT.with(&[("tunnel",&tunnel_name)]),
+                });
+                let mut listener_handles = tunnel.listener_handles.lock().await;
+                for listen_stream in listen_streams {
+                    listener_handles.push(tokio::spawn(tunnel_accept(tunnel.clone(), listen_stream)));
+                }
+                drop(listener_handles);
+                if !tunnel.listen_addrs.is_empty() {
+                    info!(tunnel = tunnel_name.as_str(); "tunnel {} service listening on address: {}",tunnel_name,tunnel.bound_addr());
+                }
+                tunnels_l.insert(key.clone(), tunnel.clone());
+                metrics::ACTIVE_TUNNELS.with(&[]).fetch_add(1, Ordering::Relaxed);
+                tunnel
+            }
         }
-    });
-    
+    };
+
+    // 通知client认证成功和实际监听的地址，只注册域名时为空
+    if session.version >= 2 {
+        let mut write_data = BOUND_ID.to_be_bytes().to_vec();
+        write_data.extend_from_slice(tunnel.bound_addr().as_bytes());
+        let mut w = session.tunnel_writer.lock().await;
+        let _ = EncWriter::new(conf.key.clone()).write_to_tunnel(&mut *w, &write_data).await;
+    }
+
     let tunnle_to_connections_tunnel_name = tunnel_name.clone();
-    
-    let tunnle_to_connections_key = auth_key.clone();
-    let connections_to_tunnel_h = Arc::new(connections_to_tunnel_h);
-    let listener_h = connections_to_tunnel_h.clone();
-    let tunnle_to_connections_key_h = tokio::spawn(async move {
+    let tunnle_to_connections_key = conf.key.clone();
+    let client_writers = session.client_writers.clone();
+    let reader_session = session.clone();
+    let bytes_out = tunnel.bytes_out.clone();
+    // session创建时为健康
+    let healthy_clients = tunnel.healthy_clients.clone();
+    healthy_clients.fetch_add(1, Ordering::Relaxed);
+    let mut pong_enc_writer = EncWriter::new(conf.key.clone());
+    let mut tunnle_to_connections_h = tokio::spawn(async move {
         let mut enc_reader = EncReader::new(tunnle_to_connections_key);
         let mut data = vec![];
         let mut len_bytes = [0;4];
@@ -152,36 +599,76 @@ async fn server_handle(mut tunnel_stream: TcpStream, tunnel_confs:Arc<HashMap<St
             let r = enc_reader.read_from_tunnel(&mut tunnel_reader, &mut data).await;
             match r {
                 Ok(_) => {
-                    for i in 0..4 {
-                        len_bytes[i] = data[i];
-                    }
+                    len_bytes.copy_from_slice(&data[..4]);
 
                     let id = u32::from_be_bytes(len_bytes);
                     let data = &data[4..];
 
-                    info!("tunnel {} connection {} write {} bytes data to client",tunnle_to_connections_tunnel_name,id,data.len());
+                    if id == PONG_ID {
+                        if let Some(rtt) = reader_session.rtt.record(data) {
+                            info!(tunnel = tunnle_to_connections_tunnel_name.as_str(); "tunnel {} client {} ping rtt {:.1}ms, {}",tunnle_to_connections_tunnel_name,reader_session.client_addr,rtt.as_secs_f64() * 1000.0,reader_session.rtt);
+                            reader_session.rtt.export(&tunnle_to_connections_tunnel_name);
+                        }
+                        continue;
+                    }
+                    if id == PING_ID {
+                        // client的PING原样回复PONG
+                        if data.len() == PING_LEN {
+                            let mut write_data = PONG_ID.to_be_bytes().to_vec();
+                            write_data.extend_from_slice(data);
+                            let mut w = reader_session.tunnel_writer.lock().await;
+                            let _ = pong_enc_writer.write_to_tunnel(&mut *w, &write_data).await;
+                        }
+                        continue;
+                    }
+                    if id == HEALTH_ID {
+                        let Some(healthy) = data.first().map(|h| *h != 0) else {
+                            error!(tunnel = tunnle_to_connections_tunnel_name.as_str(); "tunnel {} client {} sent empty health frame, ignored",tunnle_to_connections_tunnel_name,reader_session.client_addr);
+                            continue;
+                        };
+                        if reader_session.healthy.swap(healthy, Ordering::Relaxed) != healthy {
+                            if healthy {
+                                healthy_clients.fetch_add(1, Ordering::Relaxed);
+                            } else {
+                                healthy_clients.fetch_sub(1, Ordering::Relaxed);
+                            }
+                            info!(tunnel = tunnle_to_connections_tunnel_name.as_str(); "tunnel {} client {} reported healthy: {}",tunnle_to_connections_tunnel_name,reader_session.client_addr,healthy);
+                        }
+                        continue;
+                    }
+                    info!(tunnel = tunnle_to_connections_tunnel_name.as_str(), connection = id; "tunnel {} connection {} write {} bytes data to client",tunnle_to_connections_tunnel_name,id,data.len());
                     let mut l = client_writers.lock().await;
                     if id == CLOSE_ID {
-                        for i in 0..4 {
-                            len_bytes[i] = data[i];
+                        let Some(id) = data.first_chunk::<4>().map(|b| u32::from_be_bytes(*b)) else {
+                            error!(tunnel = tunnle_to_connections_tunnel_name.as_str(); "tunnel {} client {} sent short close frame, ignored",tunnle_to_connections_tunnel_name,reader_session.client_addr);
+                            continue;
+                        };
+                        if let Some(conn) = reader_session.conns.lock().await.get(&id) {
+                            conn.set_reason("backend closed");
                         }
-                        let id = u32::from_be_bytes(len_bytes);
                         if let Some(mut s) = l.remove(&id) {
                             let _ = s.shutdown().await;
-                            info!("tunnel {} connection {} closed",tunnle_to_connections_tunnel_name,id);
+                            info!(tunnel = tunnle_to_connections_tunnel_name.as_str(), connection = id; "tunnel {} connection {} closed",tunnle_to_connections_tunnel_name,id);
                         } else {
-                            error!("receive close request from tunnel {}, but not found client connection {}",tunnle_to_connections_tunnel_name,id);
+                            error!(tunnel = tunnle_to_connections_tunnel_name.as_str(), connection = id; "receive close request from tunnel {}, but not found client connection {}",tunnle_to_connections_tunnel_name,id);
                         }
                     } else {
                         let mut error = false;
                         if let Some(s) = l.get_mut(&id) {
                             let r = s.write_all(data).await;
-                            info!("tunnel {} connection {} write data to client {:?}",tunnle_to_connections_tunnel_name,id,r);
+                            info!(tunnel = tunnle_to_connections_tunnel_name.as_str(), connection = id; "tunnel {} connection {} write data to client {:?}",tunnle_to_connections_tunnel_name,id,r);
                             error = r.is_err();
+                            bytes_out.fetch_add(data.len() as u64, Ordering::Relaxed);
+                            if let Some(conn) = reader_session.conns.lock().await.get(&id) {
+                                conn.bytes_out.fetch_add(data.len() as u64, Ordering::Relaxed);
+                            }
                         } else {
-                            error!("receive data from tunnel {}, but not found client connection {}, this pkt will be dropped",tunnle_to_connections_tunnel_name,id);
+                            error!(tunnel = tunnle_to_connections_tunnel_name.as_str(), connection = id; "receive data from tunnel {}, but not found client connection {}, this pkt will be dropped",tunnle_to_connections_tunnel_name,id);
                         };
                         if error {
+                            if let Some(conn) = reader_session.conns.lock().await.get(&id) {
+                                conn.set_reason("write error");
+                            }
                             if let Some(mut s) = l.remove(&id) {
                                 let _ = s.shutdown().await;
                             };
@@ -190,53 +677,510 @@ async fn server_handle(mut tunnel_stream: TcpStream, tunnel_confs:Arc<HashMap<St
                     drop(l);
                 },
                 Err(e) => {
-                    error!("error while read from tunnel {} stream : {}, tunnel closed!",tunnle_to_connections_tunnel_name,e);
-                    listener_h.abort();
+                    error!(tunnel = tunnle_to_connections_tunnel_name.as_str(); "error while read from tunnel {} stream : {}, tunnel closed!",tunnle_to_connections_tunnel_name,e);
                     break;
                 }
             }
         }
     });
 
-    let mut write_data = vec![];
-    let mut enc_writer = EncWriter::new(auth_key);
-    loop {
-        tokio::time::sleep(Duration::from_secs(20)).await;
-        let mut w = tunnel_writer.lock().await;
-        write_data.clear();
-        write_data.extend_from_slice(&PING_ID.to_be_bytes());
-        write_data.extend_from_slice("PING".as_bytes());
-        let r = enc_writer.write_to_tunnel(&mut w, &write_data).await;
-        if r.is_err() {
-            break;
+    let ping_session = session.clone();
+    let mut enc_writer = EncWriter::new(conf.key.clone());
+    let ping = async move {
+        let mut write_data = vec![];
+        loop {
+            tokio::time::sleep(PING_INTERVAL).await;
+            let mut w = ping_session.tunnel_writer.lock().await;
+            write_data.clear();
+            write_data.extend_from_slice(&PING_ID.to_be_bytes());
+            // 旧版client不回复PONG
+            if ping_session.version >= 2 {
+                write_data.extend_from_slice(&ping::encode(ping_session.rtt.next_seq.fetch_add(1, Ordering::Relaxed)));
+            } else {
+                write_data.extend_from_slice("PING".as_bytes());
+            }
+            let r = enc_writer.write_to_tunnel(&mut *w, &write_data).await;
+            if r.is_err() {
+                break;
+            }
+            debug!("ping client success");
         }
-        info!("ping client success");
+    };
+    tokio::select! {
+        _ = &mut tunnle_to_connections_h => {},
+        _ = ping => {},
+        _ = session.kick.notified() => {
+            info!(tunnel = tunnel_name.as_str(); "tunnel {} client {} kicked",tunnel_name,client_addr);
+        },
+    }
+    info!(tunnel = tunnel_name.as_str(); "tunnel {} client {} closed",tunnel_name,client_addr);
+    tunnle_to_connections_h.abort();
+    // 等待读取任务结束后再更新健康的client数
+    if !tunnle_to_connections_h.is_finished() {
+        let _ = tunnle_to_connections_h.await;
     }
-    info!("tunnel {} closed",tunnel_name);
-    connections_to_tunnel_h.abort();
-    let handles = handles.lock().await;
+    if session.healthy.swap(false, Ordering::Relaxed) {
+        tunnel.healthy_clients.fetch_sub(1, Ordering::Relaxed);
+    }
+    let handles = session.handles.lock().await;
     for h in handles.iter() {
         h.abort();
     }
-    tunnle_to_connections_key_h.abort();
+    drop(handles);
+
+    // 最后一个client断开时关闭监听端口
+    let mut tunnels_l = tunnels.lock().await;
+    let mut sessions = tunnel.sessions.lock().await;
+    sessions.retain(|s| !Arc::ptr_eq(s, &session));
+    if sessions.is_empty() {
+        for h in tunnel.listener_handles.lock().await.drain(..) {
+            h.abort();
+        }
+        if tunnels_l.get(&key).is_some_and(|t| Arc::ptr_eq(t, &tunnel)) {
+            tunnels_l.remove(&key);
+            metrics::ACTIVE_TUNNELS.with(&[]).fetch_sub(1, Ordering::Relaxed);
+        }
+        info!(tunnel = tunnel_name.as_str(); "tunnel {} closed",tunnel_name);
+    } else {
+        info!(tunnel = tunnel_name.as_str(); "tunnel {} {} clients remaining",tunnel_name,sessions.len());
+    }
 }
 
-async fn server(addr: SocketAddr, config: HashMap<String, TcpTunnelServerConfig>) {
-    let listener = TcpListener::bind(addr).await.unwrap();
-    info!("server listening on {}", addr);
-    let config = Arc::new(config);
-    while let Ok((client_stream, _)) = listener.accept().await {
+async fn server(listener: TcpListener, kind: ListenerKind, accept_proxy: bool, tls: Option<TlsAcceptor>, config: TunnelConfs, allow_ports: Arc<Vec<u16>>, tunnels: Tunnels) {
+    while let Ok((client_stream, client_addr)) = listener.accept().await {
         let conf = config.clone();
-        tokio::spawn(server_handle(client_stream, conf));
+        let allow_ports = allow_ports.clone();
+        let tls = tls.clone();
+        let tunnels = tunnels.clone();
+        tokio::spawn(async move {
+            let mut client_stream = client_stream;
+            let mut client_addr = client_addr;
+            let mut initial_data = vec![];
+            if accept_proxy {
+                match tokio::time::timeout(PROXY_HEADER_TIMEOUT, proxy_protocol::read_header(&mut client_stream)).await {
+                    Ok(Ok((addrs,data))) => {
+                        if let Some((src,_)) = addrs {
+                            client_addr = src;
+                        }
+                        initial_data = data;
+                    }
+                    Ok(Err(e)) => {
+                        error!("tunnel connection from {} proxy protocol error: {}",client_addr,e);
+                        metrics::HANDSHAKE_FAILURES.inc(&[("reason","proxy_protocol")]);
+                        return;
+                    }
+                    Err(_) => {
+                        error!("tunnel connection from {} proxy protocol header timeout",client_addr);
+                        metrics::HANDSHAKE_FAILURES.inc(&[("reason","proxy_protocol")]);
+                        return;
+                    }
+                }
+            }
+            // tls、websocket握手和认证共用一个超时时间
+            let deadline = tokio::time::Instant::now() + HANDSHAKE_TIMEOUT;
+            match tokio::time::timeout_at(deadline, accept_transport(PrefixedStream::new(initial_data, client_stream), kind, tls)).await {
+                Ok(Ok((stream,fingerprint))) => server_handle(stream, client_addr, fingerprint, deadline, conf, allow_ports, tunnels).await,
+                Ok(Err(e)) => {
+                    error!("tunnel connection from {} handshake error: {}",client_addr,e);
+                    metrics::HANDSHAKE_FAILURES.inc(&[("reason","transport")]);
+                }
+                Err(_) => {
+                    error!("tunnel connection from {} handshake timeout",client_addr);
+                    metrics::HANDSHAKE_FAILURES.inc(&[("reason","timeout")]);
+                }
+            }
+        });
+    }
+}
+
+// host头去掉端口，转小写
+fn host_name(host: &str) -> String {
+    let name = match host.strip_prefix('[') {
+        Some(v6) => v6.split(']').next().unwrap_or(""),
+        None => host.split(':').next().unwrap_or(""),
+    };
+    name.to_lowercase()
+}
+
+async fn http_response<W: AsyncWrite + Unpin>(stream: &mut W, status: &str, content_type: &str, headers: &str, body: &str) {
+    let response = format!("HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n{}", status, content_type, body.len(), headers, body);
+    let _ = stream.write_all(response.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+// http虚拟主机端口，读取请求头中的Host，转发给注册了该域名的tunnel
+async fn http_server(listener: TcpListener, not_found: Arc<String>, tunnels: Tunnels) {
+    let Ok(addr) = listener.local_addr() else {
+        return;
+    };
+    while let Ok((mut stream, client_addr)) = listener.accept().await {
+        let not_found = not_found.clone();
+        let tunnels = tunnels.clone();
+        tokio::spawn(async move {
+            let (head,rest) = match tokio::time::timeout(HTTP_HEAD_TIMEOUT, read_http_head(&mut stream)).await {
+                Ok(Ok(r)) => r,
+                Ok(Err(e)) => {
+                    error!("http connection from {} read head error: {}",client_addr,e);
+                    return;
+                }
+                Err(_) => {
+                    error!("http connection from {} read head timeout",client_addr);
+                    return;
+                }
+            };
+            let host = http_header(&head, "Host").map(host_name).unwrap_or_default();
+            let tunnel = match find_tunnel_by_host(&tunnels, &host).await {
+                Some(tunnel) => tunnel,
+                None => {
+                    info!("http connection from {} unknown host {:?}",client_addr,host);
+                    http_response(&mut stream, "404 Not Found", HTML, "", &not_found).await;
+                    return;
+                }
+            };
+            if let Some(auth) = &tunnel.http_auth {
+                if http_header(&head, "Authorization") != Some(auth.as_str()) {
+                    info!(tunnel = tunnel.name.as_str(); "tunnel {} http connection from {} unauthorized",tunnel.name,client_addr);
+                    http_response(&mut stream, "401 Unauthorized", HTML, "WWW-Authenticate: Basic realm=\"tcp_tunnel\"\r\n", UNAUTHORIZED).await;
+                    return;
+                }
+            }
+            let head = rewrite_request(&head, tunnel.host_rewrite.as_deref(), &client_addr.ip().to_string(), "http", tunnel.http_auth.is_some());
+            let local_addr = stream.local_addr().unwrap_or(addr);
+            let mut initial_data = head.into_bytes();
+            initial_data.extend_from_slice(&rest);
+            dispatch_connection(tunnel, stream, client_addr, local_addr, initial_data).await;
+        });
+    }
+}
+
+// https透传端口，读取ClientHello中的sni，原样转发给注册了该域名的tunnel
+async fn sni_server(listener: TcpListener, tunnels: Tunnels) {
+    let Ok(addr) = listener.local_addr() else {
+        return;
+    };
+    while let Ok((mut stream, client_addr)) = listener.accept().await {
+        let tunnels = tunnels.clone();
+        tokio::spawn(async move {
+            let (sni,initial_data) = match tokio::time::timeout(HTTP_HEAD_TIMEOUT, read_client_hello(&mut stream)).await {
+                Ok(Ok(r)) => r,
+                Ok(Err(e)) => {
+                    error!("https connection from {} read client hello error: {}",client_addr,e);
+                    return;
+                }
+                Err(_) => {
+                    error!("https connection from {} read client hello timeout",client_addr);
+                    return;
+                }
+            };
+            let sni = sni.unwrap_or_default();
+            let tunnel = match find_tunnel_by_host(&tunnels, &sni).await {
+                Some(tunnel) => tunnel,
+                None => {
+                    info!("https connection from {} unknown server name {:?}",client_addr,sni);
+                    return;
+                }
+            };
+            let local_addr = stream.local_addr().unwrap_or(addr);
+            dispatch_connection(tunnel, stream, client_addr, local_addr, initial_data).await;
+        });
+    }
+}
+
+fn json_error(msg: &str) -> String {
+    format!("{{\"error\":{}}}", json_string(msg))
+}
+
+async fn tunnels_json(tunnels: &Tunnels) -> String {
+    let mut tunnels_l: Vec<Arc<Tunnel>> = tunnels.lock().await.values().cloned().collect();
+    tunnels_l.sort_by(|a,b| a.name.cmp(&b.name));
+    let mut items = vec![];
+    for tunnel in tunnels_l {
+        let mut clients = vec![];
+        for session in tunnel.sessions.lock().await.iter() {
+            let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+            let rtt = &session.rtt;
+            clients.push(format!("{{\"addr\":{},\"version\":{},\"healthy\":{},\"uptime\":{},\"connections\":{},\"rtt_ms\":{{\"last\":{:.3},\"avg\":{:.3},\"max\":{:.3},\"count\":{}}}}}",
+                json_string(&session.client_addr.to_string()), session.version, session.healthy.load(Ordering::Relaxed),
+                session.connected.elapsed().as_secs(), session.conns.lock().await.len(),
+                ms(rtt.last()), ms(rtt.avg()), ms(rtt.max()), rtt.count()));
+        }
+        let domains: Vec<String> = tunnel.domains.iter().map(|d| json_string(d)).collect();
+        items.push(format!("{{\"name\":{},\"remote_addr\":{},\"bound_addr\":{},\"domains\":[{}],\"clients\":[{}]}}",
+            json_string(&tunnel.name), json_string(&tunnel.remote_addr), json_string(&tunnel.bound_addr()), domains.join(","), clients.join(",")));
+    }
+    format!("[{}]", items.join(","))
+}
+
+// 自动分配端口的tunnel每个client单独注册，按名称查询时包括所有同名的
+async fn tunnel_sessions(tunnels: &[Arc<Tunnel>]) -> Vec<Arc<Session>> {
+    let mut sessions = vec![];
+    for tunnel in tunnels {
+        sessions.extend(tunnel.sessions.lock().await.iter().cloned());
+    }
+    sessions
+}
+
+async fn connections_json(sessions: &[Arc<Session>]) -> String {
+    let mut items = vec![];
+    for session in sessions {
+        let conns = session.conns.lock().await;
+        let mut ids: Vec<&u32> = conns.keys().collect();
+        ids.sort();
+        for id in ids {
+            let conn = &conns[id];
+            items.push(format!("{{\"id\":{},\"client\":{},\"src\":{},\"dst\":{},\"uptime\":{},\"bytes_in\":{},\"bytes_out\":{}}}",
+                id, json_string(&session.client_addr.to_string()), json_string(&conn.src.to_string()), json_string(&conn.dst.to_string()),
+                conn.started.elapsed().as_secs(), conn.bytes_in.load(Ordering::Relaxed), conn.bytes_out.load(Ordering::Relaxed)));
+        }
+    }
+    format!("[{}]", items.join(","))
+}
+
+// GET /api/tunnels
+// GET /api/tunnels/{name}/connections
+// DELETE /api/tunnels/{name}/clients/{addr}   断开client
+// DELETE /api/tunnels/{name}/connections/{id} 关闭连接
+async fn admin_request(method: &str, path: &str, tunnels: &Tunnels) -> (&'static str, String) {
+    let segments: Vec<&str> = path.split('?').next().unwrap_or("").trim_matches('/').split('/').collect();
+    let matched: Vec<Arc<Tunnel>> = match segments.as_slice() {
+        ["api","tunnels"] if method == "GET" => return ("200 OK", tunnels_json(tunnels).await),
+        ["api","tunnels",name,..] => tunnels.lock().await.values().filter(|t| t.name == *name).cloned().collect(),
+        _ => return ("404 Not Found", json_error("not found")),
+    };
+    if matched.is_empty() {
+        return ("404 Not Found", json_error("tunnel not found"));
+    }
+    let sessions = tunnel_sessions(&matched).await;
+    match (method, &segments[3..]) {
+        ("GET", ["connections"]) => ("200 OK", connections_json(&sessions).await),
+        ("DELETE", ["clients",addr]) => {
+            for session in sessions.iter() {
+                if session.client_addr.to_string() == *addr {
+                    session.kick.notify_one();
+                    return ("200 OK", "{\"ok\":true}".to_string());
+                }
+            }
+            ("404 Not Found", json_error("client not found"))
+        }
+        ("DELETE", ["connections",id]) => {
+            let id: u32 = id.parse().unwrap_or(0);
+            for session in sessions.iter() {
+                if let Some(conn) = session.conns.lock().await.get(&id) {
+                    conn.close.notify_one();
+                    return ("200 OK", "{\"ok\":true}".to_string());
+                }
+            }
+            ("404 Not Found", json_error("connection not found"))
+        }
+        _ => ("404 Not Found", json_error("not found")),
+    }
+}
+
+// 管理接口，每个连接处理一个请求
+async fn admin_server(listener: TcpListener, token: Arc<String>, tunnels: Tunnels) {
+    while let Ok((mut stream, client_addr)) = listener.accept().await {
+        let token = token.clone();
+        let tunnels = tunnels.clone();
+        tokio::spawn(async move {
+            let head = match tokio::time::timeout(HTTP_HEAD_TIMEOUT, read_http_head(&mut stream)).await {
+                Ok(Ok((head,_))) => head,
+                Ok(Err(e)) => {
+                    error!("admin connection from {} read head error: {}",client_addr,e);
+                    return;
+                }
+                Err(_) => {
+                    error!("admin connection from {} read head timeout",client_addr);
+                    return;
+                }
+            };
+            let mut request_line = head.split("\r\n").next().unwrap_or("").split(' ');
+            let method = request_line.next().unwrap_or("");
+            let path = request_line.next().unwrap_or("");
+            let authorized = http_header(&head, "Authorization").and_then(|v| v.strip_prefix("Bearer ")) == Some(token.as_str());
+            let (status,body) = if authorized {
+                admin_request(method, path, &tunnels).await
+            } else {
+                ("401 Unauthorized", json_error("unauthorized"))
+            };
+            info!("admin request from {} {} {} {}",client_addr,method,path,status);
+            http_response(&mut stream, status, JSON, "", &body).await;
+        });
+    }
+}
+
+// 重新加载tunnel配置，删除或修改了的tunnel断开所有client，client重连后按新配置认证，其余tunnel不受影响
+#[cfg(unix)]
+async fn reload(path: &str, running: &ServerConfig, tunnel_confs: &TunnelConfs, tunnels: &Tunnels) {
+    let config = match load_server_config(path) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("reload config error: {}",e);
+            return;
+        }
+    };
+    let restart = running.restart_fields(&config);
+    if !restart.is_empty() {
+        warn!("config {} changed {}, only tunnel is reloaded, restart the server to apply them",path,restart.join(", "));
+    }
+    let mut confs = tunnel_confs.write().await;
+    for name in confs.keys().filter(|name| !config.tunnel.contains_key(*name)) {
+        info!(tunnel = name.as_str(); "tunnel {} removed",name);
+    }
+    for name in config.tunnel.keys().filter(|name| !confs.contains_key(*name)) {
+        info!(tunnel = name.as_str(); "tunnel {} added",name);
+    }
+    let tunnels_l = tunnels.lock().await;
+    for tunnel in tunnels_l.values() {
+        if config.tunnel.get(&tunnel.name) != confs.get(&tunnel.name) {
+            for session in tunnel.sessions.lock().await.iter() {
+                session.kick.notify_one();
+            }
+        }
+    }
+    *confs = config.tunnel;
+    info!("config {} reloaded",path);
+}
+
+#[cfg(unix)]
+async fn reload_on_sighup(path: String, running: Arc<ServerConfig>, tunnel_confs: TunnelConfs, tunnels: Tunnels) {
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()).expect("Unable to listen for SIGHUP");
+    while hangup.recv().await.is_some() {
+        info!("received SIGHUP, reloading config {}",path);
+        reload(&path, &running, &tunnel_confs, &tunnels).await;
+    }
+}
+
+// 停止接受新连接，通知client重连，等待已有连接结束或超时
+async fn graceful_shutdown(tunnels: &Tunnels, drain_timeout: Duration) {
+    let tunnels_l: Vec<Arc<Tunnel>> = tunnels.lock().await.values().cloned().collect();
+    for tunnel in tunnels_l.iter() {
+        for h in tunnel.listener_handles.lock().await.drain(..) {
+            h.abort();
+        }
+        let mut enc_writer = EncWriter::new(tunnel.key.clone());
+        let mut write_data = GOAWAY_ID.to_be_bytes().to_vec();
+        write_data.extend_from_slice(&(drain_timeout.as_secs() as u32).to_be_bytes());
+        for session in tunnel.sessions.lock().await.iter() {
+            if session.version < 2 {
+                continue;
+            }
+            let mut w = session.tunnel_writer.lock().await;
+            let _ = enc_writer.write_to_tunnel(&mut *w, &write_data).await;
+        }
+    }
+    let deadline = tokio::time::Instant::now() + drain_timeout;
+    let mut last_active = 0;
+    loop {
+        let mut active = 0;
+        for tunnel in tunnels_l.iter() {
+            for session in tunnel.sessions.lock().await.iter() {
+                active += session.handles.lock().await.iter().filter(|h| !h.is_finished()).count();
+            }
+        }
+        if active == 0 {
+            info!("all connections finished");
+            break;
+        }
+        if tokio::time::Instant::now() >= deadline {
+            info!("drain timeout, closing {} connections",active);
+            break;
+        }
+        if active != last_active {
+            info!("waiting for {} connections to finish",active);
+            last_active = active;
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
     }
 }
 
 #[tokio::main]
 async fn main() {
-    #[cfg(target_arch="x86_64")]
-    env_logger::init();
-    let args:Vec<String> = std::env::args().collect();
-    let config = load_server_config(&args[1]);
-    let listen_addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0),config.listen_port));
-    server(listen_addr, config.tunnel).await;
+    let cli = Cli::from_env(false);
+    let mut config = match cli.load_server_config() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    // --check只检查配置，不启动
+    if cli.check {
+        println!("{}: ok", cli.source());
+        return;
+    }
+    cli.init_logger(&config.log);
+    if let Some(path) = &config.access_log {
+        if let Err(e) = logging::init_access_log(path, &config.log) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+    let listen_addrs = config.listen_addrs();
+    let allow_ports = Arc::new(config.allow_ports());
+    let tunnel_confs: TunnelConfs = Arc::new(RwLock::new(std::mem::take(&mut config.tunnel)));
+    let tunnels: Tunnels = Arc::new(Mutex::new(HashMap::new()));
+    #[cfg(feature = "tls")]
+    let tls_acceptor = match config.tls.as_ref().map(tcp_tunnel::tls::server_acceptor).transpose() {
+        Ok(tls_acceptor) => tls_acceptor,
+        Err(e) => {
+            eprintln!("unable to load tls config: {}", e);
+            std::process::exit(1);
+        }
+    };
+    #[cfg(not(feature = "tls"))]
+    let tls_acceptor: Option<TlsAcceptor> = None;
+    let not_found = match &config.http_not_found {
+        Some(path) => std::fs::read_to_string(path).unwrap_or_else(|e| {
+            eprintln!("unable to read http_not_found {}: {}", path, e);
+            std::process::exit(1);
+        }),
+        None => DEFAULT_NOT_FOUND.to_string(),
+    };
+    let not_found = Arc::new(not_found);
+    // ws、tls、http和https端口监听在与listen_addr相同的地址上
+    let mut handles = vec![];
+    for addr in listen_addrs {
+        let listener = listen_or_exit("server", addr);
+        handles.push(tokio::spawn(server(listener, ListenerKind::Auto, config.accept_proxy_protocol, tls_acceptor.clone(), tunnel_confs.clone(), allow_ports.clone(), tunnels.clone())));
+        if let Some(ws_port) = config.ws_port {
+            let listener = listen_or_exit("ws server", SocketAddr::new(addr.ip(), ws_port));
+            handles.push(tokio::spawn(server(listener, ListenerKind::Ws, config.accept_proxy_protocol, None, tunnel_confs.clone(), allow_ports.clone(), tunnels.clone())));
+        }
+        if let Some(tls_port) = config.tls.as_ref().and_then(|tls| tls.port) {
+            let listener = listen_or_exit("tls server", SocketAddr::new(addr.ip(), tls_port));
+            handles.push(tokio::spawn(server(listener, ListenerKind::Tls, config.accept_proxy_protocol, tls_acceptor.clone(), tunnel_confs.clone(), allow_ports.clone(), tunnels.clone())));
+        }
+        if let Some(http_port) = config.http_port {
+            let listener = listen_or_exit("http server", SocketAddr::new(addr.ip(), http_port));
+            handles.push(tokio::spawn(http_server(listener, not_found.clone(), tunnels.clone())));
+        }
+        if let Some(https_port) = config.https_port {
+            let listener = listen_or_exit("https server", SocketAddr::new(addr.ip(), https_port));
+            handles.push(tokio::spawn(sni_server(listener, tunnels.clone())));
+        }
+    }
+    if let Some(addr) = config.metrics_listen {
+        handles.push(tokio::spawn(metrics::serve(listen_or_exit("metrics server", addr))));
+    }
+    if let Some(admin) = &config.admin {
+        let listener = listen_or_exit("admin server", admin.listen);
+        handles.push(tokio::spawn(admin_server(listener, Arc::new(admin.token.clone()), tunnels.clone())));
+    }
+    let config = Arc::new(config);
+    // 只有使用配置文件时才能重新加载
+    #[cfg(unix)]
+    if let Some(path) = cli.config.clone() {
+        tokio::spawn(reload_on_sighup(path, config.clone(), tunnel_confs.clone(), tunnels.clone()));
+    }
+    let abort_handles: Vec<_> = handles.iter().map(|h| h.abort_handle()).collect();
+    tokio::select! {
+        _ = futures::future::join_all(handles) => {},
+        _ = shutdown_signal() => {
+            info!("shutting down, stop accepting new connections");
+            for h in abort_handles {
+                h.abort();
+            }
+            graceful_shutdown(&tunnels, Duration::from_secs(config.drain_timeout)).await;
+        },
+    }
 }
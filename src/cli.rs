@@ -0,0 +1,275 @@
+use toml::value::{Table, Value};
+
+use crate::{config::ConfigError, load_client_config, load_server_config, logging::{self, LogConfig}, ClientConfig, ServerConfig};
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+pub const SERVER_USAGE: &str = "usage: server [options] [server.toml]
+
+options:
+  -c, --config <file>      config file
+      --log-level <level>  error, warn, info, debug, trace or target=level
+      --check              check the config and exit
+  -V, --version            print version
+  -h, --help               print this help
+
+quick tunnel without config file:
+      --port <port>        listen port
+      --key <key>          key of all tunnels
+      --tunnel <name>      tunnel name, can be repeated
+
+  server --port 7000 --key 123456 --tunnel ssh";
+
+pub const CLIENT_USAGE: &str = "usage: client [options] [client.toml]
+       client status [client.toml | --socket <path>]
+
+options:
+  -c, --config <file>      config file
+      --log-level <level>  error, warn, info, debug, trace or target=level
+      --check              check the config and exit
+  -V, --version            print version
+  -h, --help               print this help
+
+status of the running client:
+      --socket <path>      control socket, default is control_socket in the config file
+
+quick tunnel without config file:
+      --server <host:port>            server address
+      --key <key>                     key of all tunnels
+      --tunnel <name:local_addr:port> local_addr forwarded to server port, can be repeated
+      --reconn <seconds>              reconnect interval, default 30
+
+  client --server example.com:7000 --key 123456 --tunnel ssh:192.168.1.1:22:2222";
+
+// 命令行参数，server和client共用
+#[derive(Default)]
+pub struct Cli {
+    pub config: Option<String>,
+    pub log_level: Option<String>,
+    pub check: bool,
+    pub version: bool,
+    pub help: bool,
+    // 不用配置文件时的快速tunnel参数
+    pub port: Option<u16>,
+    pub server: Option<String>,
+    pub key: Option<String>,
+    pub reconn: Option<u64>,
+    pub tunnels: Vec<String>,
+    // client status，通过控制socket查询运行中的client
+    pub status: bool,
+    pub socket: Option<String>,
+}
+
+impl Cli {
+    pub fn parse(args: impl Iterator<Item = String>, is_client: bool) -> Result<Cli, String> {
+        let mut cli = Cli::default();
+        let mut args = args;
+        while let Some(arg) = args.next() {
+            // 支持--name=value
+            let (name,inline_value) = match arg.split_once('=') {
+                Some((name,value)) if name.starts_with("--") => (name.to_string(), Some(value.to_string())),
+                _ => (arg.clone(), None),
+            };
+            let mut value = || inline_value.clone().or_else(|| args.next()).ok_or_else(|| format!("{} requires a value", name));
+            match name.as_str() {
+                "-c" | "--config" => cli.config = Some(value()?),
+                "--log-level" => {
+                    let level = value()?;
+                    logging::parse_filters(&level)?;
+                    cli.log_level = Some(level);
+                }
+                "--check" => cli.check = true,
+                "-V" | "--version" => cli.version = true,
+                "-h" | "--help" => cli.help = true,
+                "--key" => cli.key = Some(value()?),
+                "--tunnel" => cli.tunnels.push(value()?),
+                "--port" if !is_client => {
+                    let port = value()?;
+                    cli.port = Some(port.parse().map_err(|_| format!("invalid port {:?}", port))?);
+                }
+                "--server" if is_client => cli.server = Some(value()?),
+                "--socket" if is_client => cli.socket = Some(value()?),
+                "--reconn" if is_client => {
+                    let reconn = value()?;
+                    cli.reconn = Some(reconn.parse().map_err(|_| format!("invalid reconn {:?}", reconn))?);
+                }
+                _ if arg.starts_with('-') => return Err(format!("unknown option {}", arg)),
+                "status" if is_client && !cli.status && cli.config.is_none() => cli.status = true,
+                _ if cli.config.is_none() => cli.config = Some(arg),
+                _ => return Err(format!("unexpected argument {}", arg)),
+            }
+        }
+        let quick = cli.port.is_some() || cli.server.is_some() || cli.key.is_some() || !cli.tunnels.is_empty();
+        if quick && cli.config.is_some() {
+            return Err("quick tunnel options cannot be used with a config file".to_string());
+        }
+        if quick && cli.status {
+            return Err("quick tunnel options cannot be used with status".to_string());
+        }
+        if cli.socket.is_some() && !cli.status {
+            return Err("--socket can only be used with status".to_string());
+        }
+        if !quick && cli.config.is_none() && cli.socket.is_none() && !cli.help && !cli.version {
+            return Err("config file is required".to_string());
+        }
+        Ok(cli)
+    }
+
+    // 解析失败时打印用法并退出，--help和--version直接退出
+    pub fn from_env(is_client: bool) -> Cli {
+        let (name,usage) = if is_client { ("client",CLIENT_USAGE) } else { ("server",SERVER_USAGE) };
+        let cli = match Cli::parse(std::env::args().skip(1), is_client) {
+            Ok(cli) => cli,
+            Err(e) => {
+                eprintln!("{}\n\n{}", e, usage);
+                std::process::exit(2);
+            }
+        };
+        if cli.help {
+            println!("{}", usage);
+            std::process::exit(0);
+        }
+        if cli.version {
+            println!("{} {}", name, VERSION);
+            std::process::exit(0);
+        }
+        cli
+    }
+
+    // 配置来源，用于--check输出
+    pub fn source(&self) -> &str {
+        self.config.as_deref().unwrap_or("command line")
+    }
+
+    pub fn load_server_config(&self) -> Result<ServerConfig, ConfigError> {
+        match &self.config {
+            Some(path) => load_server_config(path),
+            None => self.server_config(),
+        }
+    }
+
+    pub fn load_client_config(&self) -> Result<ClientConfig, ConfigError> {
+        match &self.config {
+            Some(path) => load_client_config(path),
+            None => self.client_config(),
+        }
+    }
+
+    // 日志级别优先级：--log-level、RUST_LOG、配置文件
+    pub fn init_logger(&self, conf: &LogConfig) {
+        let level = self.log_level.clone().or_else(|| std::env::var("RUST_LOG").ok().filter(|l| !l.is_empty()));
+        if let Err(e) = logging::init(level.as_deref(), Some(conf)) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+
+    fn quick_config<T: serde::de::DeserializeOwned>(&self, table: Table) -> Result<T, ConfigError> {
+        Value::Table(table).try_into().map_err(|e| ConfigError::Parse { path: "command line".to_string(), line: None, col: None, msg: e.to_string() })
+    }
+
+    fn quick_key(&self) -> Result<Value, ConfigError> {
+        self.key.clone().map(Value::String).ok_or_else(|| invalid("--key", "--key is required"))
+    }
+
+    // 由命令行参数生成配置，检查规则与配置文件相同
+    fn server_config(&self) -> Result<ServerConfig, ConfigError> {
+        let mut table = Table::new();
+        let port = self.port.ok_or_else(|| invalid("--port", "--port is required"))?;
+        table.insert("listen_port".to_string(), Value::Integer(port as i64));
+        let mut tunnels = Table::new();
+        for name in self.tunnels.iter() {
+            let mut tunnel = Table::new();
+            tunnel.insert("key".to_string(), self.quick_key()?);
+            tunnels.insert(name.clone(), Value::Table(tunnel));
+        }
+        table.insert("tunnel".to_string(), Value::Table(tunnels));
+        let config: ServerConfig = self.quick_config(table)?;
+        config.validate("command line")?;
+        Ok(config)
+    }
+
+    fn client_config(&self) -> Result<ClientConfig, ConfigError> {
+        let mut table = Table::new();
+        let server = self.server.clone().ok_or_else(|| invalid("--server", "--server is required"))?;
+        table.insert("server_addr".to_string(), Value::String(server));
+        table.insert("reconn".to_string(), Value::Integer(self.reconn.unwrap_or(30) as i64));
+        let mut tunnels = Table::new();
+        for spec in self.tunnels.iter() {
+            // name:local_addr:remote_port，local_addr可以是[ipv6]:port
+            let parsed = spec.split_once(':').and_then(|(name,rest)| rest.rsplit_once(':').map(|(local,remote)| (name,local,remote)));
+            let (name,local_addr,remote_port) = parsed.ok_or_else(|| invalid("--tunnel", format!("invalid tunnel {:?}, expected name:local_addr:remote_port", spec)))?;
+            let mut tunnel = Table::new();
+            tunnel.insert("local_addr".to_string(), Value::String(local_addr.to_string()));
+            tunnel.insert("remote_addr".to_string(), Value::String(format!("0.0.0.0:{}", remote_port)));
+            tunnel.insert("key".to_string(), self.quick_key()?);
+            tunnels.insert(name.to_string(), Value::Table(tunnel));
+        }
+        if tunnels.is_empty() {
+            return Err(invalid("--tunnel", "at least one --tunnel is required"));
+        }
+        table.insert("tunnel".to_string(), Value::Table(tunnels));
+        let config: ClientConfig = self.quick_config(table)?;
+        config.validate("command line")?;
+        Ok(config)
+    }
+}
+
+fn invalid(field: &str, msg: impl Into<String>) -> ConfigError {
+    ConfigError::Invalid { path: "command line".to_string(), field: field.to_string(), msg: msg.into() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str], is_client: bool) -> Result<Cli, String> {
+        Cli::parse(args.iter().map(|a| a.to_string()), is_client)
+    }
+
+    #[test]
+    fn parse_options() {
+        let cli = parse(&["-c", "client.toml", "--log-level=info"], true).unwrap();
+        assert_eq!(cli.config.as_deref(), Some("client.toml"));
+        assert_eq!(cli.log_level.as_deref(), Some("info"));
+        let cli = parse(&["status", "--socket", "/tmp/c.sock"], true).unwrap();
+        assert!(cli.status);
+        assert_eq!(cli.socket.as_deref(), Some("/tmp/c.sock"));
+        assert!(parse(&["--port", "7000"], true).is_err());
+        assert!(parse(&["--socket", "/tmp/c.sock"], true).is_err());
+        assert!(parse(&["--key", "k", "server.toml"], false).is_err());
+        assert!(parse(&["--tunnel"], true).is_err());
+        assert!(parse(&[], false).is_err());
+    }
+
+    #[test]
+    fn quick_client_tunnels() {
+        let cli = parse(&["--server", "example.com:7000", "--key", "k", "--tunnel", "ssh:192.168.1.1:22:2222", "--tunnel", "web:[::1]:80:8080"], true).unwrap();
+        let config = cli.load_client_config().unwrap();
+        assert_eq!(config.server_addr, "example.com:7000");
+        assert_eq!(config.reconn, 30);
+        let ssh = &config.tunnel["ssh"];
+        assert_eq!(ssh.local_addr, vec!["192.168.1.1:22".to_string()]);
+        assert_eq!(ssh.remote_addr.as_deref(), Some("0.0.0.0:2222"));
+        let web = &config.tunnel["web"];
+        assert_eq!(web.local_addr, vec!["[::1]:80".to_string()]);
+        assert_eq!(web.remote_addr.as_deref(), Some("0.0.0.0:8080"));
+        assert_eq!(web.key, "k");
+    }
+
+    #[test]
+    fn quick_client_invalid_tunnel() {
+        let cli = parse(&["--server", "example.com:7000", "--key", "k", "--tunnel", "ssh"], true).unwrap();
+        assert!(cli.load_client_config().is_err());
+        let cli = parse(&["--server", "example.com:7000", "--tunnel", "ssh:127.0.0.1:22:2222"], true).unwrap();
+        assert!(cli.load_client_config().is_err());
+    }
+
+    #[test]
+    fn quick_server_tunnels() {
+        let cli = parse(&["--port", "7000", "--key", "k", "--tunnel", "ssh"], false).unwrap();
+        let config = cli.load_server_config().unwrap();
+        assert_eq!(config.listen_port, Some(7000));
+        assert_eq!(config.tunnel["ssh"].key, "k");
+    }
+}
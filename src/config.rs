@@ -0,0 +1,374 @@
+use std::{collections::HashSet, fmt, net::SocketAddr};
+
+use crate::{logging::{self, LogConfig, LogOutput}, port_range, ClientConfig, ServerConfig};
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io { path: String, err: std::io::Error },
+    // toml语法或字段类型错误，line和col从1开始
+    Parse { path: String, line: Option<usize>, col: Option<usize>, msg: String },
+    // 语义检查失败，field为出错的字段，如"tunnel.web.key"
+    Invalid { path: String, field: String, msg: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io { path, err } => write!(f, "{}: unable to read config file: {}", path, err),
+            ConfigError::Parse { path, line: Some(line), col: Some(col), msg } => write!(f, "{}:{}:{}: {}", path, line, col, msg),
+            ConfigError::Parse { path, msg, .. } => write!(f, "{}: {}", path, msg),
+            ConfigError::Invalid { path, field, msg } => write!(f, "{}: {}: {}", path, field, msg),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+pub fn read_config<T: serde::de::DeserializeOwned>(path: &str) -> Result<T, ConfigError> {
+    let config_str = std::fs::read_to_string(path).map_err(|err| ConfigError::Io { path: path.to_string(), err })?;
+    toml::from_str(&config_str).map_err(|e| {
+        let line_col = e.line_col();
+        ConfigError::Parse {
+            path: path.to_string(),
+            line: line_col.map(|(line,_)| line + 1),
+            col: line_col.map(|(_,col)| col + 1),
+            msg: e.to_string(),
+        }
+    })
+}
+
+// 收集语义错误时还不知道文件名，由load_*_config补上
+struct Invalid(String, String);
+
+impl Invalid {
+    fn into_error(self, path: &str) -> ConfigError {
+        ConfigError::Invalid { path: path.to_string(), field: self.0, msg: self.1 }
+    }
+}
+
+fn invalid(field: impl Into<String>, msg: impl Into<String>) -> Invalid {
+    Invalid(field.into(), msg.into())
+}
+
+// 认证信息中tunnel名称长度只有1字节
+fn check_tunnel(name: &str, key: &str) -> Result<(), Invalid> {
+    if name.is_empty() || name.len() > 255 {
+        return Err(invalid(format!("tunnel.{}", name), "tunnel name must be 1 to 255 bytes"));
+    }
+    if key.is_empty() {
+        return Err(invalid(format!("tunnel.{}.key", name), "key, key_file or key_env must be configured"));
+    }
+    Ok(())
+}
+
+fn check_tls_feature(has_tls: bool) -> Result<(), Invalid> {
+    if has_tls && !cfg!(feature = "tls") {
+        return Err(invalid("tls", "tls is not supported, rebuild with --features tls"));
+    }
+    Ok(())
+}
+
+fn check_log(conf: &LogConfig) -> Result<(), Invalid> {
+    if let Some(level) = &conf.level {
+        logging::parse_filters(level).map_err(|e| invalid("log.level", e))?;
+    }
+    if conf.output == LogOutput::File && conf.file.as_deref().is_none_or(|f| f.is_empty()) {
+        return Err(invalid("log.file", "file must be configured when output is file"));
+    }
+    if conf.output == LogOutput::Syslog && !cfg!(unix) && conf.syslog_addr.is_none() {
+        return Err(invalid("log.syslog_addr", "syslog_addr must be configured on this platform"));
+    }
+    if conf.max_size == 0 {
+        return Err(invalid("log.max_size", "max_size must be greater than 0"));
+    }
+    Ok(())
+}
+
+// 环境变量覆盖配置文件中的值，同一份配置文件可以用于多台设备
+pub const SERVER_ADDR_ENV: &str = "TCP_TUNNEL_SERVER_ADDR";
+pub const LISTEN_PORT_ENV: &str = "TCP_TUNNEL_LISTEN_PORT";
+
+// 把key_file或key_env读取到key中，三者只能设置一个
+fn resolve_key(name: &str, key: &mut String, key_file: &Option<String>, key_env: &Option<String>) -> Result<(), Invalid> {
+    let sources = [!key.is_empty(), key_file.is_some(), key_env.is_some()];
+    if sources.iter().filter(|set| **set).count() > 1 {
+        return Err(invalid(format!("tunnel.{}.key", name), "only one of key, key_file and key_env can be set"));
+    }
+    if let Some(path) = key_file {
+        let field = format!("tunnel.{}.key_file", name);
+        *key = std::fs::read_to_string(path).map_err(|e| invalid(&field, format!("{}: {}", path, e)))?.trim().to_string();
+        if key.is_empty() {
+            return Err(invalid(field, format!("{} is empty", path)));
+        }
+    }
+    if let Some(var) = key_env {
+        let field = format!("tunnel.{}.key_env", name);
+        *key = std::env::var(var).map_err(|e| invalid(&field, format!("{}: {}", var, e)))?;
+        if key.is_empty() {
+            return Err(invalid(field, format!("{} is empty", var)));
+        }
+    }
+    Ok(())
+}
+
+fn env_override(var: &str) -> Option<String> {
+    std::env::var(var).ok().filter(|v| !v.trim().is_empty())
+}
+
+// 按名称排序，错误信息稳定
+fn sorted<V>(tunnels: &std::collections::HashMap<String, V>) -> Vec<(&String, &V)> {
+    let mut tunnels: Vec<(&String, &V)> = tunnels.iter().collect();
+    tunnels.sort_by_key(|(name,_)| *name);
+    tunnels
+}
+
+impl ServerConfig {
+    pub(crate) fn resolve(&mut self, path: &str) -> Result<(), ConfigError> {
+        self.apply_env().map_err(|e| e.into_error(path))
+    }
+
+    fn apply_env(&mut self) -> Result<(), Invalid> {
+        // 设置了listen_addr时覆盖其中所有地址的端口
+        if let Some(port) = env_override(LISTEN_PORT_ENV) {
+            let port: u16 = port.trim().parse().map_err(|_| invalid(LISTEN_PORT_ENV, format!("invalid port {:?}", port)))?;
+            self.listen_port = Some(port);
+            for addr in self.listen_addr.iter_mut() {
+                addr.set_port(port);
+            }
+        }
+        let mut names: Vec<String> = self.tunnel.keys().cloned().collect();
+        names.sort();
+        for name in names {
+            let conf = self.tunnel.get_mut(&name).unwrap();
+            resolve_key(&name, &mut conf.key, &conf.key_file, &conf.key_env)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn validate(&self, path: &str) -> Result<(), ConfigError> {
+        self.check().map_err(|e| e.into_error(path))
+    }
+
+    fn check(&self) -> Result<(), Invalid> {
+        if self.listen_addrs().is_empty() {
+            return Err(invalid("listen_port", "listen_port or listen_addr must be configured"));
+        }
+        check_tls_feature(self.tls.is_some())?;
+        // 启动前加载证书和私钥，--check时也能发现错误
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &self.tls {
+            crate::tls::server_acceptor(tls).map_err(|e| invalid("tls", e.to_string()))?;
+        }
+        if let Some(path) = &self.http_not_found {
+            std::fs::read_to_string(path).map_err(|e| invalid("http_not_found", format!("unable to read {}: {}", path, e)))?;
+        }
+        check_log(&self.log)?;
+        if self.access_log.as_deref().is_some_and(|f| f.is_empty()) {
+            return Err(invalid("access_log", "access_log must not be empty"));
+        }
+        // 同一个地址上的端口不能重复
+        let mut ports = HashSet::new();
+        let named_ports = [
+            ("ws_port", self.ws_port),
+            ("http_port", self.http_port),
+            ("https_port", self.https_port),
+            ("tls.port", self.tls.as_ref().and_then(|tls| tls.port)),
+            ("admin.listen", self.admin.as_ref().map(|admin| admin.listen.port())),
+            ("metrics_listen", self.metrics_listen.map(|addr| addr.port())),
+        ];
+        for addr in self.listen_addrs() {
+            ports.insert(addr.port());
+        }
+        for (field,port) in named_ports {
+            if let Some(port) = port {
+                if !ports.insert(port) {
+                    return Err(invalid(field, format!("port {} is already used", port)));
+                }
+            }
+        }
+        if self.admin.as_ref().is_some_and(|admin| admin.token.is_empty()) {
+            return Err(invalid("admin.token", "token must not be empty"));
+        }
+        for range in self.allow_ports.iter() {
+            if port_range(&format!("0.0.0.0:{}", range.trim())).is_none() {
+                return Err(invalid("allow_ports", format!("invalid port range {:?}", range)));
+            }
+        }
+        for (name,conf) in sorted(&self.tunnel) {
+            check_tunnel(name, &conf.key)?;
+            if conf.http_password.is_some() && conf.http_user.is_none() {
+                return Err(invalid(format!("tunnel.{}.http_user", name), "http_password is set without http_user"));
+            }
+        }
+        Ok(())
+    }
+}
+
+// 两个监听地址是否可能冲突，0.0.0.0和[::]与任何地址冲突
+fn same_listen_ip(a: &SocketAddr, b: &SocketAddr) -> bool {
+    a.ip() == b.ip() || a.ip().is_unspecified() || b.ip().is_unspecified()
+}
+
+impl ClientConfig {
+    pub(crate) fn resolve(&mut self, path: &str) -> Result<(), ConfigError> {
+        self.apply_env().map_err(|e| e.into_error(path))
+    }
+
+    fn apply_env(&mut self) -> Result<(), Invalid> {
+        if let Some(server_addr) = env_override(SERVER_ADDR_ENV) {
+            self.server_addr = server_addr.trim().to_string();
+        }
+        let mut names: Vec<String> = self.tunnel.keys().cloned().collect();
+        names.sort();
+        for name in names {
+            let conf = self.tunnel.get_mut(&name).unwrap();
+            resolve_key(&name, &mut conf.key, &conf.key_file, &conf.key_env)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn validate(&self, path: &str) -> Result<(), ConfigError> {
+        self.check().map_err(|e| e.into_error(path))
+    }
+
+    fn check(&self) -> Result<(), Invalid> {
+        if port_range(&self.server_addr).is_none_or(|(host,start,end)| host.is_empty() || start != end) {
+            return Err(invalid("server_addr", format!("invalid address {:?}, expected host:port", self.server_addr)));
+        }
+        check_tls_feature(self.tls.is_some())?;
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &self.tls {
+            crate::tls::client_connector(tls).map_err(|e| invalid("tls", e.to_string()))?;
+        }
+        check_log(&self.log)?;
+        if let Some(path) = &self.control_socket {
+            if path.is_empty() {
+                return Err(invalid("control_socket", "control_socket must not be empty"));
+            }
+            if !cfg!(unix) {
+                return Err(invalid("control_socket", "control_socket is not supported on this platform"));
+            }
+        }
+        // (tunnel名称,监听地址,起始端口,结束端口)，用于检查端口重复
+        let mut remote_ports: Vec<(&str, SocketAddr, u16, u16)> = vec![];
+        for (name,conf) in sorted(&self.tunnel) {
+            check_tunnel(name, &conf.key)?;
+            if conf.local_addr.is_empty() {
+                return Err(invalid(format!("tunnel.{}.local_addr", name), "local_addr must not be empty"));
+            }
+            if conf.connect_timeout == 0 {
+                return Err(invalid(format!("tunnel.{}.connect_timeout", name), "connect_timeout must be greater than 0"));
+            }
+            if let Some(health_check) = &conf.health_check {
+                if health_check.interval == 0 {
+                    return Err(invalid(format!("tunnel.{}.health_check.interval", name), "interval must be greater than 0"));
+                }
+                if health_check.timeout == 0 {
+                    return Err(invalid(format!("tunnel.{}.health_check.timeout", name), "timeout must be greater than 0"));
+                }
+            }
+            let remote_addr = match &conf.remote_addr {
+                Some(remote_addr) => remote_addr,
+                None if conf.custom_domains.is_empty() => {
+                    return Err(invalid(format!("tunnel.{}.remote_addr", name), "remote_addr or custom_domains must be configured"));
+                }
+                None => continue,
+            };
+            let field = format!("tunnel.{}.remote_addr", name);
+            let (host,start,end) = port_range(remote_addr).ok_or_else(|| invalid(&field, format!("invalid address {:?}", remote_addr)))?;
+            let addr: SocketAddr = format!("{}:{}", host, start).parse().map_err(|_| invalid(&field, format!("invalid address {:?}", remote_addr)))?;
+            // 端口0表示由server分配，不能作为端口范围的起点
+            if start == 0 && end != 0 {
+                return Err(invalid(&field, format!("port range {} must not start at 0", remote_addr)));
+            }
+            // local_addr为端口范围时长度必须与remote_addr相同
+            for local_addr in conf.local_addr.iter() {
+                if let Some((_,local_start,local_end)) = port_range(local_addr) {
+                    if local_start != local_end && local_end - local_start != end - start {
+                        return Err(invalid(format!("tunnel.{}.local_addr", name), format!("port range {} does not match remote_addr {}", local_addr, remote_addr)));
+                    }
+                }
+            }
+            // 端口为0时由server分配，不会冲突
+            if start == 0 {
+                continue;
+            }
+            if let Some((other,..)) = remote_ports.iter().find(|(_,a,s,e)| same_listen_ip(a, &addr) && start <= *e && *s <= end) {
+                return Err(invalid(&field, format!("port {} overlaps with tunnel {}", remote_addr, other)));
+            }
+            remote_ports.push((name, addr, start, end));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 返回出错的字段
+    fn check_client(tunnels: &str) -> Result<(), String> {
+        let conf: ClientConfig = toml::from_str(&format!("server_addr = \"127.0.0.1:7000\"\nreconn = 5\n{}", tunnels)).unwrap();
+        conf.check().map_err(|e| e.0)
+    }
+
+    fn check_server(config: &str) -> Result<(), String> {
+        let conf: ServerConfig = toml::from_str(config).unwrap();
+        conf.check().map_err(|e| e.0)
+    }
+
+    #[test]
+    fn client_tunnel_name_and_key() {
+        assert_eq!(check_client("[tunnel.a]\nlocal_addr = \"127.0.0.1:22\"\nremote_addr = \"0.0.0.0:2222\"\nkey = \"k\""), Ok(()));
+        let long = "a".repeat(256);
+        let r = check_client(&format!("[tunnel.{}]\nlocal_addr = \"127.0.0.1:22\"\nremote_addr = \"0.0.0.0:2222\"\nkey = \"k\"", long));
+        assert_eq!(r, Err(format!("tunnel.{}", long)));
+        let r = check_client("[tunnel.a]\nlocal_addr = \"127.0.0.1:22\"\nremote_addr = \"0.0.0.0:2222\"\nkey = \"\"");
+        assert_eq!(r, Err("tunnel.a.key".to_string()));
+    }
+
+    #[test]
+    fn key_sources() {
+        let mut key = "k".to_string();
+        assert!(resolve_key("a", &mut key, &None, &Some("TCP_TUNNEL_TEST_KEY".to_string())).is_err_and(|e| e.0 == "tunnel.a.key"));
+        let mut key = String::new();
+        assert!(resolve_key("a", &mut key, &Some("k.txt".to_string()), &Some("TCP_TUNNEL_TEST_KEY".to_string())).is_err_and(|e| e.0 == "tunnel.a.key"));
+        let mut key = "k".to_string();
+        assert!(resolve_key("a", &mut key, &None, &None).is_ok());
+    }
+
+    #[test]
+    fn client_remote_ports() {
+        let tunnel = |name: &str, remote: &str| format!("[tunnel.{}]\nlocal_addr = \"127.0.0.1:22\"\nremote_addr = \"{}\"\nkey = \"k\"\n", name, remote);
+        // 相同端口、范围重叠、0.0.0.0与其他地址
+        let r = check_client(&(tunnel("a", "0.0.0.0:2222") + &tunnel("b", "0.0.0.0:2222")));
+        assert_eq!(r, Err("tunnel.b.remote_addr".to_string()));
+        let r = check_client(&(tunnel("a", "0.0.0.0:3000-3010") + &tunnel("b", "127.0.0.1:3010-3020")));
+        assert_eq!(r, Err("tunnel.b.remote_addr".to_string()));
+        assert_eq!(check_client(&(tunnel("a", "127.0.0.1:2222") + &tunnel("b", "127.0.0.2:2222"))), Ok(()));
+        assert_eq!(check_client(&(tunnel("a", "0.0.0.0:3000-3010") + &tunnel("b", "0.0.0.0:3011"))), Ok(()));
+        // 端口为0时由server分配
+        assert_eq!(check_client(&(tunnel("a", "0.0.0.0:0") + &tunnel("b", "0.0.0.0:0"))), Ok(()));
+        assert_eq!(check_client(&tunnel("a", "0.0.0.0:0-10")), Err("tunnel.a.remote_addr".to_string()));
+    }
+
+    #[test]
+    fn client_local_range() {
+        let r = check_client("[tunnel.a]\nlocal_addr = \"127.0.0.1:3000-3005\"\nremote_addr = \"0.0.0.0:4000-4010\"\nkey = \"k\"");
+        assert_eq!(r, Err("tunnel.a.local_addr".to_string()));
+        assert_eq!(check_client("[tunnel.a]\nlocal_addr = \"127.0.0.1:3000-3010\"\nremote_addr = \"0.0.0.0:4000-4010\"\nkey = \"k\""), Ok(()));
+        assert_eq!(check_client("[tunnel.a]\nlocal_addr = \"127.0.0.1:3000\"\nremote_addr = \"0.0.0.0:4000-4010\"\nkey = \"k\""), Ok(()));
+    }
+
+    #[test]
+    fn server_check() {
+        assert_eq!(check_server("listen_port = 7000\n[tunnel.a]\nkey = \"k\""), Ok(()));
+        assert_eq!(check_server("[tunnel.a]\nkey = \"k\""), Err("listen_port".to_string()));
+        assert_eq!(check_server("listen_port = 7000\nhttp_port = 7000\n[tunnel.a]\nkey = \"k\""), Err("http_port".to_string()));
+        assert_eq!(check_server("listen_port = 7000\nallow_ports = [\"40000-\"]\n[tunnel.a]\nkey = \"k\""), Err("allow_ports".to_string()));
+        assert_eq!(check_server("listen_port = 7000\nhttp_not_found = \"/nonexistent/404.html\"\n[tunnel.a]\nkey = \"k\""), Err("http_not_found".to_string()));
+        assert_eq!(check_server("listen_port = 7000\n[tunnel.a]\nkey = \"\""), Err("tunnel.a.key".to_string()));
+        assert_eq!(check_server(&format!("listen_port = 7000\n[tunnel.{}]\nkey = \"k\"", "a".repeat(256))), Err(format!("tunnel.{}", "a".repeat(256))));
+        assert_eq!(check_server("listen_port = 7000\n[tunnel.a]\nkey = \"k\"\nhttp_password = \"p\""), Err("tunnel.a.http_user".to_string()));
+    }
+}
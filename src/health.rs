@@ -0,0 +1,67 @@
+use std::time::Duration;
+
+use serde::Deserialize;
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream};
+
+#[derive(Deserialize,Clone,Copy,PartialEq,Default,Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthCheckType {
+    #[default]
+    Tcp,
+    Http,
+}
+
+fn default_interval() -> u64 {
+    10
+}
+
+fn default_timeout() -> u64 {
+    3
+}
+
+fn default_path() -> String {
+    "/".to_string()
+}
+
+#[derive(Deserialize,Clone,PartialEq)]
+pub struct HealthCheckConfig {
+    #[serde(default, rename = "type")]
+    pub check_type: HealthCheckType,
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    // http检查的路径，返回2xx或3xx为健康
+    #[serde(default = "default_path")]
+    pub path: String,
+}
+
+async fn check_once(conf:&HealthCheckConfig,addr:&str) -> Result<(),String> {
+    let mut stream = TcpStream::connect(addr).await.map_err(|e| e.to_string())?;
+    if conf.check_type == HealthCheckType::Tcp {
+        return Ok(());
+    }
+    let request = format!("GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n", conf.path, addr);
+    stream.write_all(request.as_bytes()).await.map_err(|e| e.to_string())?;
+    let mut buf = [0u8;64];
+    let mut n = 0;
+    while n < 12 {
+        let r = stream.read(&mut buf[n..]).await.map_err(|e| e.to_string())?;
+        if r == 0 {
+            break;
+        }
+        n += r;
+    }
+    let status_line = String::from_utf8_lossy(&buf[..n]);
+    match status_line.split(' ').nth(1) {
+        Some(code) if code.starts_with('2') || code.starts_with('3') => Ok(()),
+        _ => Err(format!("unexpected response {:?}", status_line.lines().next().unwrap_or(""))),
+    }
+}
+
+pub async fn check(conf:&HealthCheckConfig,addr:&str) -> Result<(),String> {
+    match tokio::time::timeout(Duration::from_secs(conf.timeout), check_once(conf, addr)).await {
+        Ok(r) => r,
+        Err(_) => Err("timeout".to_string()),
+    }
+}
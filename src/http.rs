@@ -0,0 +1,157 @@
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const MAX_HEAD_LEN: usize = 8192;
+
+pub fn base64_encode(data:&[u8]) -> String {
+    const TABLE: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = ((b[0] as u32) << 16) | ((b[1] as u32) << 8) | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(TABLE[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+// 带引号的json字符串
+pub fn json_string(s:&str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// 读取http头直到空行，返回头部文本和多读出来的数据
+pub async fn read_http_head<S: AsyncRead + Unpin>(stream:&mut S) -> tokio::io::Result<(String,Vec<u8>)> {
+    let mut data = vec![];
+    let mut buf = [0u8;1024];
+    loop {
+        if let Some(pos) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+            let rest = data.split_off(pos + 4);
+            let head = String::from_utf8_lossy(&data).to_string();
+            return Ok((head,rest));
+        }
+        if data.len() > MAX_HEAD_LEN {
+            return Err(tokio::io::Error::new(tokio::io::ErrorKind::InvalidData, "http head too large"));
+        }
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Err(tokio::io::Error::new(tokio::io::ErrorKind::UnexpectedEof, "connection closed while reading http head"));
+        }
+        data.extend_from_slice(&buf[..n]);
+    }
+}
+
+pub fn http_header<'a>(head:&'a str,name:&str) -> Option<&'a str> {
+    head.split("\r\n").skip(1).find_map(|line| {
+        let (k,v) = line.split_once(':')?;
+        if k.trim().eq_ignore_ascii_case(name) {
+            Some(v.trim())
+        } else {
+            None
+        }
+    })
+}
+
+// 转发给tunnel前改写请求头：替换Host，追加X-Forwarded-For，设置X-Forwarded-Proto；
+// 只能改写连接上的第一个请求，所以除了websocket升级外都改成Connection: close
+// strip_auth为true时Authorization是server认证用的，不转发给后端
+pub fn rewrite_request(head:&str,host:Option<&str>,forwarded_for:&str,proto:&str,strip_auth:bool) -> String {
+    let upgrade = http_header(head, "Upgrade").is_some();
+    let mut lines = head.trim_end_matches("\r\n").split("\r\n");
+    let mut out = lines.next().unwrap_or("").to_string();
+    out.push_str("\r\n");
+    let mut forwarded = forwarded_for.to_string();
+    for line in lines {
+        let (name,value) = line.split_once(':').unwrap_or((line,""));
+        let name = name.trim();
+        if name.eq_ignore_ascii_case("X-Forwarded-For") {
+            forwarded = format!("{}, {}", value.trim(), forwarded_for);
+            continue;
+        }
+        if name.eq_ignore_ascii_case("X-Forwarded-Proto")
+            || (host.is_some() && name.eq_ignore_ascii_case("Host"))
+            || (strip_auth && name.eq_ignore_ascii_case("Authorization"))
+            || (!upgrade && (name.eq_ignore_ascii_case("Connection") || name.eq_ignore_ascii_case("Keep-Alive") || name.eq_ignore_ascii_case("Proxy-Connection"))) {
+            continue;
+        }
+        out.push_str(line);
+        out.push_str("\r\n");
+    }
+    if let Some(host) = host {
+        out.push_str(&format!("Host: {}\r\n", host));
+    }
+    out.push_str(&format!("X-Forwarded-For: {}\r\nX-Forwarded-Proto: {}\r\n", forwarded, proto));
+    if !upgrade {
+        out.push_str("Connection: close\r\n");
+    }
+    out.push_str("\r\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64_padding() {
+        assert_eq!(base64_encode(b""), "");
+        assert_eq!(base64_encode(b"f"), "Zg==");
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+        assert_eq!(base64_encode(b"foo"), "Zm9v");
+        assert_eq!(base64_encode(b"foob"), "Zm9vYg==");
+        assert_eq!(base64_encode(b"user:pass"), "dXNlcjpwYXNz");
+    }
+
+    #[test]
+    fn rewrite_host_and_forwarded_for() {
+        let head = "GET / HTTP/1.1\r\nHost: a.example.com\r\nX-Forwarded-For: 10.0.0.1\r\nX-Forwarded-Proto: https\r\n\r\n";
+        let out = rewrite_request(head, Some("192.168.1.1"), "1.2.3.4", "http", false);
+        assert_eq!(http_header(&out, "Host"), Some("192.168.1.1"));
+        assert_eq!(http_header(&out, "X-Forwarded-For"), Some("10.0.0.1, 1.2.3.4"));
+        assert_eq!(http_header(&out, "X-Forwarded-Proto"), Some("http"));
+        assert_eq!(out.matches("Host:").count(), 1);
+        assert!(out.ends_with("\r\n\r\n"));
+        let out = rewrite_request(head, None, "1.2.3.4", "http", false);
+        assert_eq!(http_header(&out, "Host"), Some("a.example.com"));
+    }
+
+    #[test]
+    fn rewrite_connection() {
+        let head = "GET / HTTP/1.1\r\nHost: a\r\nConnection: keep-alive\r\nKeep-Alive: timeout=5\r\n\r\n";
+        let out = rewrite_request(head, None, "1.2.3.4", "http", false);
+        assert_eq!(http_header(&out, "Connection"), Some("close"));
+        assert_eq!(http_header(&out, "Keep-Alive"), None);
+        // websocket升级保留Connection
+        let head = "GET /ws HTTP/1.1\r\nHost: a\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n";
+        let out = rewrite_request(head, None, "1.2.3.4", "http", false);
+        assert_eq!(http_header(&out, "Connection"), Some("Upgrade"));
+        assert_eq!(out.matches("Connection:").count(), 1);
+    }
+
+    #[test]
+    fn rewrite_authorization() {
+        let head = "GET / HTTP/1.1\r\nHost: a\r\nAuthorization: Basic dXNlcjpwYXNz\r\n\r\n";
+        let out = rewrite_request(head, None, "1.2.3.4", "http", true);
+        assert_eq!(http_header(&out, "Authorization"), None);
+        let out = rewrite_request(head, None, "1.2.3.4", "http", false);
+        assert_eq!(http_header(&out, "Authorization"), Some("Basic dXNlcjpwYXNz"));
+    }
+}
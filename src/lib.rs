@@ -1,47 +1,461 @@
-use std::{collections::HashMap, net::SocketAddr};
+use std::{collections::HashMap, net::{Ipv4Addr, SocketAddr}, pin::Pin, task::{Context, Poll}};
 
-use serde::Deserialize;
-use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{tcp::{OwnedReadHalf, OwnedWriteHalf}, TcpListener}};
+use serde::{Deserialize, Deserializer};
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::{balance::Balance, config::ConfigError, health::HealthCheckConfig, logging::LogConfig, proxy_protocol::ProxyProtocol};
+use tokio::{io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf, ReadHalf, WriteHalf}, net::TcpListener};
+
+pub mod balance;
+pub mod cli;
+pub mod config;
+pub mod health;
+pub mod http;
+pub mod logging;
+pub mod metrics;
+pub mod ping;
+pub mod proxy_protocol;
+pub mod sni;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod ws;
 
 #[derive(Deserialize)]
 pub struct ServerConfig {
-    pub listen_port: u16,
+    pub listen_port: Option<u16>,
+    // 监听地址列表，如["[::]:7000"]，设置后忽略listen_port
+    #[serde(default)]
+    pub listen_addr: Vec<SocketAddr>,
+    pub ws_port: Option<u16>,
+    // 控制端口前面有负载均衡时，解析PROXY protocol头获取client真实地址
+    #[serde(default)]
+    pub accept_proxy_protocol: bool,
+    // http虚拟主机端口，按Host头把请求转发到注册了该域名的tunnel
+    pub http_port: Option<u16>,
+    // 未知域名时返回的404页面文件
+    pub http_not_found: Option<String>,
+    // https透传端口，按ClientHello中的sni把连接转发到注册了该域名的tunnel，不解密tls
+    pub https_port: Option<u16>,
+    // client的remote_addr端口为0时从这些端口中随机分配，如["40000-40100"]，不设置时由系统分配
+    #[serde(default)]
+    pub allow_ports: Vec<String>,
+    // 退出时等待已有连接结束的最长时间，秒
+    #[serde(default = "default_drain_timeout")]
+    pub drain_timeout: u64,
+    pub tls: Option<ServerTlsConfig>,
+    // 管理接口，查询在线tunnel和连接，断开client或连接
+    pub admin: Option<AdminConfig>,
+    // prometheus指标监听地址
+    pub metrics_listen: Option<SocketAddr>,
+    #[serde(default)]
+    pub log: LogConfig,
+    // 访问日志文件，每个公网连接结束时记录一行
+    pub access_log: Option<String>,
     pub tunnel: HashMap<String,TcpTunnelServerConfig>
 }
 
-#[derive(Deserialize,Clone)]
+fn default_drain_timeout() -> u64 {
+    30
+}
+
+impl ServerConfig {
+    pub fn allow_ports(&self) -> Vec<u16> {
+        let mut ports = vec![];
+        // 格式已经在load_server_config中检查
+        for range in self.allow_ports.iter() {
+            if let Some((_,start,end)) = port_range(&format!("0.0.0.0:{}", range.trim())) {
+                ports.extend(start..=end);
+            }
+        }
+        ports
+    }
+
+    // 重新加载只更新tunnel，其余配置修改后需要重启才能生效
+    pub fn restart_fields(&self, other: &ServerConfig) -> Vec<&'static str> {
+        let fields = [
+            ("listen_port", self.listen_port != other.listen_port),
+            ("listen_addr", self.listen_addr != other.listen_addr),
+            ("ws_port", self.ws_port != other.ws_port),
+            ("accept_proxy_protocol", self.accept_proxy_protocol != other.accept_proxy_protocol),
+            ("http_port", self.http_port != other.http_port),
+            ("http_not_found", self.http_not_found != other.http_not_found),
+            ("https_port", self.https_port != other.https_port),
+            ("allow_ports", self.allow_ports != other.allow_ports),
+            ("drain_timeout", self.drain_timeout != other.drain_timeout),
+            ("tls", self.tls != other.tls),
+            ("admin", self.admin != other.admin),
+            ("metrics_listen", self.metrics_listen != other.metrics_listen),
+            ("log", self.log != other.log),
+            ("access_log", self.access_log != other.access_log),
+        ];
+        fields.into_iter().filter(|(_,changed)| *changed).map(|(name,_)| name).collect()
+    }
+
+    pub fn listen_addrs(&self) -> Vec<SocketAddr> {
+        if !self.listen_addr.is_empty() {
+            return self.listen_addr.clone();
+        }
+        self.listen_port
+            .map(|port| vec![SocketAddr::from((Ipv4Addr::UNSPECIFIED, port))])
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Deserialize,Clone,PartialEq)]
+pub struct ServerTlsConfig {
+    pub cert: String,
+    pub key: String,
+    // 单独的tls端口，不设置时listen_port自动识别tls连接
+    pub port: Option<u16>,
+}
+
+#[derive(Deserialize,Clone,PartialEq)]
+pub struct AdminConfig {
+    // 建议只监听127.0.0.1
+    pub listen: SocketAddr,
+    // 请求头Authorization: Bearer <token>
+    pub token: String,
+}
+
+#[derive(Deserialize,Clone,PartialEq)]
 pub struct TcpTunnelServerConfig {
+    // 也可以用key_file或key_env，加载时读取到key中
+    #[serde(default)]
     pub key: String,
+    // 从文件读取key，去掉首尾空白
+    pub key_file: Option<String>,
+    // 从环境变量读取key
+    pub key_env: Option<String>,
+    // 设置后该tunnel必须使用tls并提供指纹匹配的客户端证书
+    pub client_fingerprint: Option<String>,
+    // 多个client连接同一个tunnel时的负载均衡策略
+    #[serde(default)]
+    pub balance: Balance,
+    // 公网端口前面有负载均衡时，解析PROXY protocol头获取真实来源地址
+    #[serde(default)]
+    pub accept_proxy_protocol: bool,
+    // 设置后通过http_port访问该tunnel需要http basic认证
+    pub http_user: Option<String>,
+    pub http_password: Option<String>,
 }
 
+// 小于CONNECTION_ID_START的id为控制帧
 pub static PING_ID:u32 = 0;
 pub static CLOSE_ID:u32 = 1;
+// client上报后端健康状态，1字节，1健康0不健康
+pub static HEALTH_ID:u32 = 2;
+// server通知client新连接，连接id之后为"源地址 目标地址 端口范围中的位置"
+pub static OPEN_ID:u32 = 3;
+// server认证成功后通知client实际监听的地址，remote_addr端口为0时由server分配，只注册域名时为空
+pub static BOUND_ID:u32 = 4;
+// server即将退出，client应立即重连，已有连接继续传输直到结束，4字节的等待秒数
+pub static GOAWAY_ID:u32 = 5;
+// 回复PING，内容与PING相同，用于计算往返时间
+pub static PONG_ID:u32 = 6;
 pub static CONNECTION_ID_START: u32 = 10;
 
-pub fn load_server_config(file_path: &str) -> ServerConfig {
-    let config_str = std::fs::read_to_string(file_path).expect("Unable to read config file");
-    let config: ServerConfig = toml::from_str(&config_str).unwrap();
-    config
+// 1为旧版client，不支持OPEN等新的控制帧
+pub static PROTOCOL_VERSION: u32 = 2;
+
+// 认证信息结束标记，之后的数据为隧道数据帧
+pub static HANDSHAKE_END: &[u8] = b"\n\n";
+
+// 认证信息，第一行为remote_addr，之后每行一个key=value选项
+pub struct Handshake {
+    pub remote_addr: String,
+    pub options: Vec<(String,String)>,
+}
+
+impl Handshake {
+    pub fn new(remote_addr:String) -> Self {
+        Handshake {
+            remote_addr,
+            options: vec![("version".to_string(), PROTOCOL_VERSION.to_string())],
+        }
+    }
+
+    pub fn parse(text:&str) -> Self {
+        let mut lines = text.split('\n');
+        let remote_addr = lines.next().unwrap_or("").to_string();
+        let options = lines
+            .filter_map(|line| line.split_once('='))
+            .map(|(k,v)| (k.to_string(), v.to_string()))
+            .collect();
+        Handshake { remote_addr, options }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut text = self.remote_addr.clone();
+        for (k,v) in self.options.iter() {
+            text.push('\n');
+            text.push_str(k);
+            text.push('=');
+            text.push_str(v);
+        }
+        let mut data = text.into_bytes();
+        data.extend_from_slice(HANDSHAKE_END);
+        data
+    }
+
+    pub fn option(&self,key:&str) -> Option<&str> {
+        self.options.iter().find(|(k,_)| k == key).map(|(_,v)| v.as_str())
+    }
+
+    pub fn version(&self) -> u32 {
+        self.option("version").and_then(|v| v.parse().ok()).unwrap_or(1)
+    }
+}
+
+// 读取配置，处理key_file、key_env和环境变量覆盖后检查
+pub fn load_server_config(file_path: &str) -> Result<ServerConfig,ConfigError> {
+    let mut config: ServerConfig = config::read_config(file_path)?;
+    config.resolve(file_path)?;
+    config.validate(file_path)?;
+    Ok(config)
+}
+
+#[derive(Deserialize,Clone,Copy,PartialEq,Default,Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    #[default]
+    Tcp,
+    Ws,
+}
+
+fn default_connect_timeout() -> u64 {
+    5
+}
+
+fn default_ws_path() -> String {
+    "/".to_string()
 }
 
 #[derive(Deserialize)]
 pub struct ClientConfig {
-    pub server_addr: SocketAddr,
+    // host:port，支持域名
+    pub server_addr: String,
     pub reconn: u64,
+    #[serde(default)]
+    pub transport: Transport,
+    #[serde(default = "default_ws_path")]
+    pub ws_path: String,
+    pub ws_host: Option<String>,
+    pub tls: Option<ClientTlsConfig>,
+    #[serde(default = "default_drain_timeout")]
+    pub drain_timeout: u64,
+    // prometheus指标监听地址
+    pub metrics_listen: Option<SocketAddr>,
+    // 本地控制socket路径，client status通过它查询运行状态，只支持unix
+    pub control_socket: Option<String>,
+    #[serde(default)]
+    pub log: LogConfig,
     pub tunnel: HashMap<String,TcpTunnelClientConfig>
 }
 
-#[derive(Deserialize,Clone)]
+impl ClientConfig {
+    // 连接server的配置是否相同，不同时所有tunnel都要重连
+    pub fn same_server(&self,other:&ClientConfig) -> bool {
+        self.server_addr == other.server_addr
+            && self.reconn == other.reconn
+            && self.transport == other.transport
+            && self.ws_path == other.ws_path
+            && self.ws_host == other.ws_host
+            && self.tls == other.tls
+    }
+
+    // 重新加载时不生效，修改后需要重启的配置
+    pub fn restart_fields(&self, other: &ClientConfig) -> Vec<&'static str> {
+        let fields = [
+            ("metrics_listen", self.metrics_listen != other.metrics_listen),
+            ("control_socket", self.control_socket != other.control_socket),
+            ("log", self.log != other.log),
+        ];
+        fields.into_iter().filter(|(_,changed)| *changed).map(|(name,_)| name).collect()
+    }
+}
+
+#[derive(Deserialize,Clone,PartialEq)]
+pub struct ClientTlsConfig {
+    // sni，默认为server_addr的地址
+    pub server_name: Option<String>,
+    // 服务端证书sha256指纹，设置后不再校验证书链
+    pub fingerprint: Option<String>,
+    pub ca: Option<String>,
+    pub cert: Option<String>,
+    pub key: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(s) => vec![s],
+        OneOrMany::Many(v) => v,
+    })
+}
+
+#[derive(Deserialize,Clone,PartialEq)]
 pub struct TcpTunnelClientConfig {
-    pub remote_addr: SocketAddr,
-    pub local_addr: SocketAddr,
+    // 只使用custom_domains时可以不设置，支持端口范围"0.0.0.0:30000-30010"
+    pub remote_addr: Option<String>,
+    // 在server的http_port上注册的域名，支持"*.example.com"
+    #[serde(default)]
+    pub custom_domains: Vec<String>,
+    // 通过http_port访问时替换请求的Host头
+    pub host_header_rewrite: Option<String>,
+    // 一个或多个后端地址，remote_addr为端口范围时可以是同样长度的端口范围
+    #[serde(deserialize_with = "one_or_many")]
+    pub local_addr: Vec<String>,
+    #[serde(default)]
+    pub balance: Balance,
+    // 连接一个后端的超时时间，秒，超时后尝试下一个后端
+    #[serde(default = "default_connect_timeout")]
+    pub connect_timeout: u64,
+    pub health_check: Option<HealthCheckConfig>,
+    // 连接后端时先发送PROXY protocol头，携带公网连接的真实地址
+    pub proxy_protocol: Option<ProxyProtocol>,
+    #[serde(default)]
     pub key: String,
+    pub key_file: Option<String>,
+    pub key_env: Option<String>,
+}
+
+pub fn load_client_config(file_path: &str) -> Result<ClientConfig,ConfigError> {
+    let mut config: ClientConfig = config::read_config(file_path)?;
+    config.resolve(file_path)?;
+    config.validate(file_path)?;
+    Ok(config)
+}
+
+// 等待SIGINT或SIGTERM
+pub async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()).expect("Unable to listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {},
+            _ = terminate.recv() => {},
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+// 隧道底层可以是tcp、websocket等，统一成一个trait object
+pub trait TunnelStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> TunnelStream for T {}
+
+pub type BoxedStream = Box<dyn TunnelStream>;
+pub type TunnelReader = ReadHalf<BoxedStream>;
+pub type TunnelWriter = WriteHalf<BoxedStream>;
+
+// 嗅探协议时已经读出的数据，需要在后续读取时先返回
+pub struct PrefixedStream<S> {
+    prefix: Vec<u8>,
+    pos: usize,
+    inner: S,
+}
+
+impl<S> PrefixedStream<S> {
+    pub fn new(prefix:Vec<u8>,inner:S) -> Self {
+        PrefixedStream { prefix, pos: 0, inner }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for PrefixedStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if this.pos < this.prefix.len() {
+            let n = (this.prefix.len() - this.pos).min(buf.remaining());
+            buf.put_slice(&this.prefix[this.pos..this.pos + n]);
+            this.pos += n;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for PrefixedStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
 }
 
-pub fn load_client_config(file_path: &str) -> ClientConfig {
-    let config_str = std::fs::read_to_string(file_path).expect("Unable to read config file");
-    let config: ClientConfig = toml::from_str(&config_str).unwrap();
-    config
+// [::]监听时同时接受ipv4连接，windows默认是只接受ipv6
+pub fn bind_listener(addr:SocketAddr) -> tokio::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() && addr.ip().is_unspecified() {
+        socket.set_only_v6(false)?;
+    }
+    #[cfg(not(windows))]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into())
+}
+
+// 启动时监听端口，失败时打印错误并退出
+pub fn listen_or_exit(name:&str,addr:SocketAddr) -> TcpListener {
+    match bind_listener(addr) {
+        Ok(listener) => {
+            log::info!("{} listening on {}", name, addr);
+            listener
+        }
+        Err(e) => {
+            eprintln!("unable to listen on {} for {}: {}", addr, name, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+// host:port中的host部分，去掉ipv6的方括号
+pub fn addr_host(addr:&str) -> &str {
+    let host = addr.rsplit_once(':').map(|(host,_)| host).unwrap_or(addr);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+// 解析"host:port"或端口范围"host:start-end"，返回(host,起始端口,结束端口)
+pub fn port_range(addr:&str) -> Option<(&str,u16,u16)> {
+    let (host,ports) = addr.rsplit_once(':')?;
+    let (start,end) = match ports.split_once('-') {
+        Some((start,end)) => (start.parse().ok()?, end.parse().ok()?),
+        None => {
+            let port = ports.parse().ok()?;
+            (port,port)
+        }
+    };
+    if start > end {
+        return None;
+    }
+    Some((host,start,end))
+}
+
+// 端口范围中第offset个端口的地址，不是端口范围时原样返回，超出范围时返回None
+pub fn range_addr(addr:&str,offset:u16) -> Option<String> {
+    match port_range(addr) {
+        Some((host,start,end)) if start != end => (offset <= end - start).then(|| format!("{}:{}", host, start + offset)),
+        _ => Some(addr.to_string()),
+    }
+}
+
+// 配置中的证书指纹允许带冒号和大写
+pub fn normalize_fingerprint(s:&str) -> String {
+    s.chars().filter(|c| *c != ':').collect::<String>().to_lowercase()
 }
 
 pub fn xor(data:&[u8],key:&[u8],output:&mut Vec<u8>) {
@@ -65,12 +479,14 @@ impl EncWriter {
         }
     }
 
-    pub async fn write_to_tunnel(&mut self,tunnel_writer:&mut OwnedWriteHalf,data:&[u8]) -> tokio::io::Result<usize> {
+    pub async fn write_to_tunnel<W: AsyncWrite + Unpin>(&mut self,tunnel_writer:&mut W,data:&[u8]) -> tokio::io::Result<usize> {
+        // 长度和数据一次写出，websocket下对应一个消息
         self.pkt_cache.clear();
+        self.pkt_cache.extend_from_slice(&(data.len() as u32).to_be_bytes());
         xor(data, self.key.as_bytes(), &mut self.pkt_cache);
-        tunnel_writer.write_u32(self.pkt_cache.len() as u32).await?;
-        let r = tunnel_writer.write(&self.pkt_cache).await?;
-        Ok(r + 4)
+        tunnel_writer.write_all(&self.pkt_cache).await?;
+        tunnel_writer.flush().await?;
+        Ok(self.pkt_cache.len())
     }
 }
 
@@ -101,7 +517,7 @@ impl EncReader {
         }
     }
 
-    pub async fn read_from_tunnel(&mut self,tunnel_reader:&mut OwnedReadHalf,data:&mut Vec<u8>) -> tokio::io::Result<()> {
+    pub async fn read_from_tunnel<R: AsyncRead + Unpin>(&mut self,tunnel_reader:&mut R,data:&mut Vec<u8>) -> tokio::io::Result<()> {
         loop {
             if self.need_read {
                 let n = tunnel_reader.read(&mut self.buf).await?;  
@@ -117,7 +533,10 @@ impl EncReader {
                     // self.output_cahce.clear();
                     xor(&self.pkt_cache[4..self.pkt_len], self.key.as_bytes(), data);
                     
-                    assert!(data.len() > 4);
+                    // 至少包含4字节的id，控制帧可以没有数据
+                    if data.len() < 4 {
+                        return Err(tokio::io::Error::new(tokio::io::ErrorKind::InvalidData, "frame shorter than its id"));
+                    }
 
                     // for i in 0..4 {
                     //     self.len_bytes[i] = self.da[i];
@@ -131,9 +550,7 @@ impl EncReader {
                     self.next_pkt_cache.extend_from_slice(next);
                     // 检查下一个包，如果长度大于4，可能是一个完整的包
                     if self.next_pkt_cache.len() > 4 {
-                        for i in 0..4 {
-                            self.len_bytes[i] = self.next_pkt_cache[i];
-                        }
+                        self.len_bytes.copy_from_slice(&self.next_pkt_cache[..4]);
                         let len = u32::from_be_bytes(self.len_bytes);
                         // 说明不是一个完整的包，退出循环，继续读取
                         if len as usize + 4 > self.next_pkt_cache.len() {
@@ -164,12 +581,16 @@ impl EncReader {
                     self.need_read = true;
                 }
             } else {
-                assert!(self.pkt_cache.len() >= 4);
-                for i in 0..4 {
-                    self.len_bytes[i] = self.pkt_cache[i];
+                // 长度字段可能被拆分在多次读取中
+                if self.pkt_cache.len() < 4 {
+                    self.need_read = true;
+                    continue;
                 }
+                self.len_bytes.copy_from_slice(&self.pkt_cache[..4]);
                 self.pkt_data_len = u32::from_be_bytes(self.len_bytes);
-                assert!(self.pkt_data_len != 0);
+                if self.pkt_data_len < 4 {
+                    return Err(tokio::io::Error::new(tokio::io::ErrorKind::InvalidData, "frame shorter than its id"));
+                }
                 self.pkt_len = self.pkt_data_len as usize + 4;
                 self.need_read = self.pkt_len > self.pkt_cache.len();
             }
@@ -0,0 +1,307 @@
+use std::{fs::{File, OpenOptions}, io::Write, net::{SocketAddr, UdpSocket}, sync::{Mutex, OnceLock}, time::{Instant, SystemTime, UNIX_EPOCH}};
+
+use log::{kv::{self, Key, Value, VisitSource}, Level, LevelFilter, Log, Metadata, Record};
+use serde::Deserialize;
+
+use crate::http::json_string;
+
+#[derive(Deserialize,Clone,Copy,PartialEq,Default,Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum LogOutput {
+    #[default]
+    Stderr,
+    File,
+    Syslog,
+}
+
+#[derive(Deserialize,Clone,Copy,PartialEq,Default,Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+fn default_max_size() -> u64 {
+    10
+}
+
+fn default_max_files() -> usize {
+    3
+}
+
+#[derive(Deserialize,Clone,PartialEq)]
+pub struct LogConfig {
+    // 与RUST_LOG格式相同，如"info"或"info,server=debug"
+    pub level: Option<String>,
+    #[serde(default)]
+    pub output: LogOutput,
+    pub file: Option<String>,
+    // 日志文件超过max_size(MB)时轮转，保留max_files个旧文件
+    #[serde(default = "default_max_size")]
+    pub max_size: u64,
+    #[serde(default = "default_max_files")]
+    pub max_files: usize,
+    // 默认/dev/log，也可以是udp地址，如"192.168.1.1:514"
+    pub syslog_addr: Option<String>,
+    #[serde(default)]
+    pub format: LogFormat,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: None,
+            output: LogOutput::default(),
+            file: None,
+            max_size: default_max_size(),
+            max_files: default_max_files(),
+            syslog_addr: None,
+            format: LogFormat::default(),
+        }
+    }
+}
+
+// 解析日志级别，如"info"或"info,server=debug"
+pub fn parse_filters(spec: &str) -> Result<Vec<(Option<String>, LevelFilter)>, String> {
+    let mut filters = vec![];
+    for directive in spec.split(',').map(|d| d.trim()).filter(|d| !d.is_empty()) {
+        let (target,level) = match directive.split_once('=') {
+            Some((target,level)) => (Some(target.trim().to_string()), level.trim()),
+            None => (None, directive),
+        };
+        let level = level.parse::<LevelFilter>().map_err(|_| format!("invalid log level {:?}", level))?;
+        filters.push((target, level));
+    }
+    Ok(filters)
+}
+
+struct RotatingFile {
+    path: String,
+    file: File,
+    size: u64,
+    max_size: u64,
+    max_files: usize,
+}
+
+impl RotatingFile {
+    fn open(path: &str, max_size: u64, max_files: usize) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile { path: path.to_string(), file, size, max_size: max_size * 1024 * 1024, max_files })
+    }
+
+    // server.log -> server.log.1 -> server.log.2 ...
+    fn rotate(&mut self) -> std::io::Result<()> {
+        for i in (1..self.max_files).rev() {
+            let _ = std::fs::rename(format!("{}.{}", self.path, i), format!("{}.{}", self.path, i + 1));
+        }
+        if self.max_files > 0 {
+            std::fs::rename(&self.path, format!("{}.1", self.path))?;
+        }
+        self.file = OpenOptions::new().create(true).write(true).truncate(true).open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn write(&mut self, line: &[u8]) {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            if let Err(e) = self.rotate() {
+                eprintln!("rotate log file {} error: {}", self.path, e);
+            }
+        }
+        if self.file.write_all(line).is_ok() {
+            self.size += line.len() as u64;
+        }
+    }
+}
+
+enum Writer {
+    Stderr,
+    File(RotatingFile),
+    #[cfg(unix)]
+    Syslog(std::os::unix::net::UnixDatagram),
+    SyslogUdp(UdpSocket, String),
+}
+
+struct Logger {
+    filters: Vec<(Option<String>, LevelFilter)>,
+    format: LogFormat,
+    // syslog中的程序名
+    ident: String,
+    writer: Mutex<Writer>,
+}
+
+impl Logger {
+    // 匹配最长的target前缀，与env_logger相同
+    fn level(&self, target: &str) -> LevelFilter {
+        self.filters.iter()
+            .filter(|(t,_)| t.as_ref().is_none_or(|t| target.starts_with(t.as_str())))
+            .max_by_key(|(t,_)| t.as_ref().map(|t| t.len()))
+            .map(|(_,level)| *level)
+            .unwrap_or(LevelFilter::Error)
+    }
+}
+
+// 日志调用通过key-value传入tunnel、connection等字段，json格式下原样输出
+struct JsonFields<'a>(&'a mut String);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let value = match value.to_u64() {
+            Some(n) => n.to_string(),
+            None => json_string(&value.to_string()),
+        };
+        self.0.push_str(&format!(",{}:{}", json_string(key.as_str()), value));
+        Ok(())
+    }
+}
+
+// rfc3339 utc时间，不依赖时间库
+fn timestamp() -> String {
+    format_time(SystemTime::now())
+}
+
+fn format_time(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (days,rem) = (secs / 86400, secs % 86400);
+    // civil_from_days
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, rem / 3600, rem % 3600 / 60, rem % 60)
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let msg = record.args().to_string();
+        let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        let syslog = !matches!(*writer, Writer::Stderr | Writer::File(_));
+        let line = match self.format {
+            LogFormat::Text if syslog => msg,
+            LogFormat::Text => format!("[{} {:<5} {}] {}", timestamp(), record.level(), record.target(), msg),
+            LogFormat::Json => {
+                let mut line = format!("{{\"time\":\"{}\",\"level\":\"{}\",\"target\":{}", timestamp(), record.level(), json_string(record.target()));
+                let _ = record.key_values().visit(&mut JsonFields(&mut line));
+                line.push_str(&format!(",\"message\":{}}}", json_string(&msg)));
+                line
+            }
+        };
+        // syslog的daemon facility
+        let severity = match record.level() {
+            Level::Error => 3,
+            Level::Warn => 4,
+            Level::Info => 6,
+            Level::Debug | Level::Trace => 7,
+        };
+        let syslog_line = || format!("<{}>{}[{}]: {}", 3 * 8 + severity, self.ident, std::process::id(), line);
+        match &mut *writer {
+            Writer::Stderr => eprintln!("{}", line),
+            Writer::File(file) => file.write(format!("{}\n", line).as_bytes()),
+            #[cfg(unix)]
+            Writer::Syslog(socket) => {
+                let _ = socket.send(syslog_line().as_bytes());
+            }
+            Writer::SyslogUdp(socket,addr) => {
+                let _ = socket.send_to(syslog_line().as_bytes(), addr.as_str());
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Writer::File(file) = &mut *self.writer.lock().unwrap_or_else(|e| e.into_inner()) {
+            let _ = file.file.flush();
+        }
+    }
+}
+
+fn open_writer(conf: &LogConfig) -> std::io::Result<Writer> {
+    match conf.output {
+        LogOutput::Stderr => Ok(Writer::Stderr),
+        LogOutput::File => {
+            let path = conf.file.as_deref().unwrap_or_default();
+            Ok(Writer::File(RotatingFile::open(path, conf.max_size, conf.max_files)?))
+        }
+        LogOutput::Syslog => match conf.syslog_addr.as_deref() {
+            #[cfg(unix)]
+            None => {
+                let socket = std::os::unix::net::UnixDatagram::unbound()?;
+                socket.connect("/dev/log")?;
+                Ok(Writer::Syslog(socket))
+            }
+            #[cfg(not(unix))]
+            None => Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "syslog_addr is required")),
+            Some(addr) => Ok(Writer::SyslogUdp(UdpSocket::bind("0.0.0.0:0")?, addr.to_string())),
+        },
+    }
+}
+
+// level为命令行参数或RUST_LOG，优先于配置文件；都没有时只输出error
+pub fn init(level: Option<&str>, conf: Option<&LogConfig>) -> Result<(), String> {
+    let default_conf = LogConfig::default();
+    let conf = conf.unwrap_or(&default_conf);
+    let spec = level.or(conf.level.as_deref()).unwrap_or("error");
+    let filters = parse_filters(spec)?;
+    let writer = open_writer(conf).map_err(|e| format!("unable to open log output: {}", e))?;
+    let ident = std::env::args().next()
+        .and_then(|arg0| std::path::Path::new(&arg0).file_name().map(|n| n.to_string_lossy().to_string()))
+        .unwrap_or_else(|| "tcp_tunnel".to_string());
+    let max_level = filters.iter().map(|(_,level)| *level).max().unwrap_or(LevelFilter::Error);
+    let logger = Logger { filters, format: conf.format, ident, writer: Mutex::new(writer) };
+    log::set_logger(Box::leak(Box::new(logger))).map_err(|e| e.to_string())?;
+    log::set_max_level(max_level);
+    Ok(())
+}
+
+// 访问日志，每个公网连接结束时写一行，轮转和格式与[log]相同
+struct AccessLog {
+    format: LogFormat,
+    file: Mutex<RotatingFile>,
+}
+
+static ACCESS_LOG: OnceLock<AccessLog> = OnceLock::new();
+
+pub fn init_access_log(path: &str, conf: &LogConfig) -> Result<(), String> {
+    let file = RotatingFile::open(path, conf.max_size, conf.max_files).map_err(|e| format!("unable to open access log {}: {}", path, e))?;
+    ACCESS_LOG.set(AccessLog { format: conf.format, file: Mutex::new(file) }).map_err(|_| "access log already initialized".to_string())
+}
+
+pub struct AccessRecord<'a> {
+    pub tunnel: &'a str,
+    pub id: u32,
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+    pub started: Instant,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub reason: &'a str,
+}
+
+pub fn access(record: &AccessRecord) {
+    let Some(access_log) = ACCESS_LOG.get() else {
+        return;
+    };
+    let duration = record.started.elapsed();
+    let start = format_time(SystemTime::now().checked_sub(duration).unwrap_or(UNIX_EPOCH));
+    let duration = duration.as_secs_f64();
+    let line = match access_log.format {
+        LogFormat::Text => format!("{} tunnel={} connection={} src={} dst={} start={} duration={:.3}s bytes_in={} bytes_out={} reason={}\n",
+            timestamp(), record.tunnel, record.id, record.src, record.dst, start, duration, record.bytes_in, record.bytes_out, json_string(record.reason)),
+        LogFormat::Json => format!("{{\"time\":\"{}\",\"tunnel\":{},\"connection\":{},\"src\":\"{}\",\"dst\":\"{}\",\"start\":\"{}\",\"duration\":{:.3},\"bytes_in\":{},\"bytes_out\":{},\"reason\":{}}}\n",
+            timestamp(), json_string(record.tunnel), record.id, record.src, record.dst, start, duration, record.bytes_in, record.bytes_out, json_string(record.reason)),
+    };
+    access_log.file.lock().unwrap_or_else(|e| e.into_inner()).write(line.as_bytes());
+}
@@ -0,0 +1,125 @@
+use std::{collections::BTreeMap, fmt::Write, sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex}, time::Duration};
+
+use log::error;
+use tokio::{io::AsyncWriteExt, net::TcpListener};
+
+use crate::http::read_http_head;
+
+// prometheus文本格式，不依赖prometheus库，减小体积
+pub struct Metric {
+    name: &'static str,
+    help: &'static str,
+    kind: &'static str,
+    // 值为微秒，输出时换算成秒
+    micros: bool,
+}
+
+impl Metric {
+    const fn counter(name: &'static str, help: &'static str) -> Self {
+        Metric { name, help, kind: "counter", micros: false }
+    }
+
+    const fn gauge(name: &'static str, help: &'static str) -> Self {
+        Metric { name, help, kind: "gauge", micros: false }
+    }
+
+    const fn seconds(name: &'static str, help: &'static str) -> Self {
+        Metric { name, help, kind: "gauge", micros: true }
+    }
+
+    // 取得一组标签对应的值，转发循环中先取得再直接累加，避免每次查找
+    pub fn with(&'static self, labels: &[(&str, &str)]) -> Arc<AtomicU64> {
+        let mut label_text = String::new();
+        for (i,(k,v)) in labels.iter().enumerate() {
+            if i > 0 {
+                label_text.push(',');
+            }
+            let v = v.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+            let _ = write!(label_text, "{}=\"{}\"", k, v);
+        }
+        let mut registry = REGISTRY.lock().unwrap();
+        registry.entry((self.name, label_text)).or_insert_with(|| (self, Arc::new(AtomicU64::new(0)))).1.clone()
+    }
+
+    pub fn inc(&'static self, labels: &[(&str, &str)]) {
+        self.with(labels).fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// 离开作用域时减少gauge，任务被中止时也能正确计数
+pub struct GaugeGuard(Arc<AtomicU64>);
+
+impl GaugeGuard {
+    pub fn new(gauge: Arc<AtomicU64>) -> Self {
+        gauge.fetch_add(1, Ordering::Relaxed);
+        GaugeGuard(gauge)
+    }
+}
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+type Registry = BTreeMap<(&'static str, String), (&'static Metric, Arc<AtomicU64>)>;
+
+static REGISTRY: Mutex<Registry> = Mutex::new(BTreeMap::new());
+
+pub static ACTIVE_TUNNELS: Metric = Metric::gauge("tcp_tunnel_active_tunnels", "Tunnels with a connected client.");
+pub static ACTIVE_CONNECTIONS: Metric = Metric::gauge("tcp_tunnel_active_connections", "Forwarded connections currently open.");
+pub static BYTES_IN: Metric = Metric::counter("tcp_tunnel_bytes_in_total", "Bytes forwarded from public connections to the local service.");
+pub static BYTES_OUT: Metric = Metric::counter("tcp_tunnel_bytes_out_total", "Bytes forwarded from the local service to public connections.");
+pub static CONNECTIONS: Metric = Metric::counter("tcp_tunnel_connections_total", "Forwarded connections by result: accepted, refused or failed.");
+pub static HANDSHAKE_FAILURES: Metric = Metric::counter("tcp_tunnel_handshake_failures_total", "Tunnel handshakes that failed, by reason.");
+pub static BACKEND_HEALTHY: Metric = Metric::gauge("tcp_tunnel_backend_healthy", "Backend health of a tunnel: 1 or 0 on the client, clients reporting healthy backends on the server.");
+pub static RECONNECTS: Metric = Metric::counter("tcp_tunnel_reconnects_total", "Reconnects of the client to the server.");
+pub static PING_RTT: Metric = Metric::seconds("tcp_tunnel_ping_rtt_seconds", "Round-trip time of tunnel pings: last, avg or max.");
+
+pub fn render() -> String {
+    let registry = REGISTRY.lock().unwrap();
+    let mut out = String::new();
+    let mut last = "";
+    for ((name,labels),(metric,value)) in registry.iter() {
+        if *name != last {
+            let _ = writeln!(out, "# HELP {} {}", name, metric.help);
+            let _ = writeln!(out, "# TYPE {} {}", name, metric.kind);
+            last = name;
+        }
+        let value = value.load(Ordering::Relaxed);
+        let value = if metric.micros { (value as f64 / 1e6).to_string() } else { value.to_string() };
+        if labels.is_empty() {
+            let _ = writeln!(out, "{} {}", name, value);
+        } else {
+            let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+        }
+    }
+    out
+}
+
+const HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+// 任意路径都返回全部指标
+pub async fn serve(listener: TcpListener) {
+    // 没有tunnel时也输出0
+    ACTIVE_TUNNELS.with(&[]);
+    while let Ok((mut stream, client_addr)) = listener.accept().await {
+        tokio::spawn(async move {
+            match tokio::time::timeout(HEAD_TIMEOUT, read_http_head(&mut stream)).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => {
+                    error!("metrics connection from {} read head error: {}",client_addr,e);
+                    return;
+                }
+                Err(_) => {
+                    error!("metrics connection from {} read head timeout",client_addr);
+                    return;
+                }
+            }
+            let body = render();
+            let response = format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body);
+            let _ = stream.write_all(response.as_bytes()).await;
+            let _ = stream.shutdown().await;
+        });
+    }
+}
@@ -0,0 +1,84 @@
+use std::{sync::{atomic::{AtomicU32, AtomicU64, Ordering}, OnceLock}, time::{Duration, Instant}};
+
+use crate::metrics;
+
+// PING和PONG的内容：4字节序号 + 8字节发送时间（微秒，相对于进程启动）
+// 旧版的PING内容为"PING"，收到后不回复PONG
+pub const PING_LEN: usize = 12;
+
+pub const PING_INTERVAL: Duration = Duration::from_secs(20);
+
+static START: OnceLock<Instant> = OnceLock::new();
+
+fn now_micros() -> u64 {
+    START.get_or_init(Instant::now).elapsed().as_micros() as u64
+}
+
+pub fn encode(seq: u32) -> Vec<u8> {
+    let mut data = seq.to_be_bytes().to_vec();
+    data.extend_from_slice(&now_micros().to_be_bytes());
+    data
+}
+
+pub fn decode(data: &[u8]) -> Option<(u32, u64)> {
+    if data.len() != PING_LEN {
+        return None;
+    }
+    let seq = u32::from_be_bytes(data[..4].try_into().ok()?);
+    let sent = u64::from_be_bytes(data[4..].try_into().ok()?);
+    Some((seq, sent))
+}
+
+// 一个隧道连接的往返时间统计，单位微秒
+#[derive(Default)]
+pub struct RttStats {
+    pub next_seq: AtomicU32,
+    last: AtomicU64,
+    sum: AtomicU64,
+    max: AtomicU64,
+    count: AtomicU64,
+}
+
+impl RttStats {
+    // 收到PONG时记录，返回本次往返时间
+    pub fn record(&self, pong: &[u8]) -> Option<Duration> {
+        let (_,sent) = decode(pong)?;
+        let rtt = now_micros().saturating_sub(sent);
+        self.last.store(rtt, Ordering::Relaxed);
+        self.sum.fetch_add(rtt, Ordering::Relaxed);
+        self.max.fetch_max(rtt, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        Some(Duration::from_micros(rtt))
+    }
+
+    pub fn last(&self) -> Duration {
+        Duration::from_micros(self.last.load(Ordering::Relaxed))
+    }
+
+    pub fn avg(&self) -> Duration {
+        let count = self.count.load(Ordering::Relaxed);
+        Duration::from_micros(self.sum.load(Ordering::Relaxed).checked_div(count).unwrap_or(0))
+    }
+
+    pub fn max(&self) -> Duration {
+        Duration::from_micros(self.max.load(Ordering::Relaxed))
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    // 同一个tunnel有多个client时为最近一次收到PONG的client
+    pub fn export(&self, tunnel_name: &str) {
+        for (stat,value) in [("last",self.last()),("avg",self.avg()),("max",self.max())] {
+            metrics::PING_RTT.with(&[("tunnel",tunnel_name),("stat",stat)]).store(value.as_micros() as u64, Ordering::Relaxed);
+        }
+    }
+}
+
+impl std::fmt::Display for RttStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        write!(f, "last {:.1}ms avg {:.1}ms max {:.1}ms", ms(self.last()), ms(self.avg()), ms(self.max()))
+    }
+}
@@ -0,0 +1,236 @@
+use std::net::{IpAddr, SocketAddr};
+
+use serde::Deserialize;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+#[derive(Deserialize,Clone,Copy,PartialEq,Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyProtocol {
+    V1,
+    V2,
+}
+
+// (源地址,目标地址)
+pub type PeerAddrs = (SocketAddr,SocketAddr);
+
+const V2_SIGNATURE: &[u8;12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+// 源地址和目标地址协议族不同时，ipv4转换成ipv4映射的ipv6地址
+fn same_family(src:SocketAddr,dst:SocketAddr) -> (SocketAddr,SocketAddr) {
+    let to_v6 = |a:SocketAddr| match a.ip() {
+        IpAddr::V4(ip) => SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), a.port()),
+        IpAddr::V6(_) => a,
+    };
+    if src.is_ipv4() == dst.is_ipv4() {
+        (src,dst)
+    } else {
+        (to_v6(src),to_v6(dst))
+    }
+}
+
+// addrs为(源地址,目标地址)，未知时v1发送UNKNOWN，v2发送LOCAL
+pub fn encode_header(version:ProxyProtocol,addrs:Option<PeerAddrs>) -> Vec<u8> {
+    let addrs = addrs.map(|(src,dst)| same_family(src, dst));
+    match version {
+        ProxyProtocol::V1 => match addrs {
+            Some((src,dst)) => {
+                let family = if src.is_ipv4() { "TCP4" } else { "TCP6" };
+                format!("PROXY {} {} {} {} {}\r\n", family, src.ip(), dst.ip(), src.port(), dst.port()).into_bytes()
+            }
+            None => b"PROXY UNKNOWN\r\n".to_vec(),
+        },
+        ProxyProtocol::V2 => {
+            let mut header = V2_SIGNATURE.to_vec();
+            match addrs {
+                Some((src,dst)) => {
+                    header.push(0x21);
+                    let mut body = vec![];
+                    match (src.ip(),dst.ip()) {
+                        (IpAddr::V4(s),IpAddr::V4(d)) => {
+                            header.push(0x11);
+                            body.extend_from_slice(&s.octets());
+                            body.extend_from_slice(&d.octets());
+                        }
+                        (IpAddr::V6(s),IpAddr::V6(d)) => {
+                            header.push(0x21);
+                            body.extend_from_slice(&s.octets());
+                            body.extend_from_slice(&d.octets());
+                        }
+                        _ => unreachable!(),
+                    }
+                    body.extend_from_slice(&src.port().to_be_bytes());
+                    body.extend_from_slice(&dst.port().to_be_bytes());
+                    header.extend_from_slice(&(body.len() as u16).to_be_bytes());
+                    header.extend_from_slice(&body);
+                }
+                None => {
+                    header.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+                }
+            }
+            header
+        }
+    }
+}
+
+fn invalid_header(msg:&str) -> tokio::io::Error {
+    tokio::io::Error::new(tokio::io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn parse_v1(line:&str) -> Option<PeerAddrs> {
+    let parts: Vec<&str> = line.split(' ').collect();
+    if parts.len() != 6 || (parts[1] != "TCP4" && parts[1] != "TCP6") {
+        return None;
+    }
+    let src = SocketAddr::new(parts[2].parse().ok()?, parts[4].parse().ok()?);
+    let dst = SocketAddr::new(parts[3].parse().ok()?, parts[5].parse().ok()?);
+    Some((src,dst))
+}
+
+fn parse_v2(family:u8,body:&[u8]) -> Option<PeerAddrs> {
+    match family {
+        0x11 if body.len() >= 12 => {
+            let src = <[u8;4]>::try_from(&body[0..4]).ok()?;
+            let dst = <[u8;4]>::try_from(&body[4..8]).ok()?;
+            let sport = u16::from_be_bytes([body[8], body[9]]);
+            let dport = u16::from_be_bytes([body[10], body[11]]);
+            Some((SocketAddr::from((src, sport)), SocketAddr::from((dst, dport))))
+        }
+        0x21 if body.len() >= 36 => {
+            let src = <[u8;16]>::try_from(&body[0..16]).ok()?;
+            let dst = <[u8;16]>::try_from(&body[16..32]).ok()?;
+            let sport = u16::from_be_bytes([body[32], body[33]]);
+            let dport = u16::from_be_bytes([body[34], body[35]]);
+            Some((SocketAddr::from((src, sport)), SocketAddr::from((dst, dport))))
+        }
+        _ => None,
+    }
+}
+
+// 解析完整的头部时返回(地址,头部长度)，数据不够时返回None
+fn parse_header(data:&[u8]) -> tokio::io::Result<Option<(Option<PeerAddrs>,usize)>> {
+    let n = data.len().min(V2_SIGNATURE.len());
+    if data[..n] == V2_SIGNATURE[..n] {
+        if data.len() < 16 {
+            return Ok(None);
+        }
+        let len = u16::from_be_bytes([data[14], data[15]]) as usize;
+        if data.len() < 16 + len {
+            return Ok(None);
+        }
+        let addrs = match data[12] {
+            0x21 => parse_v2(data[13], &data[16..16 + len]),
+            0x20 => None,
+            _ => return Err(invalid_header("unsupported proxy protocol v2 command")),
+        };
+        return Ok(Some((addrs, 16 + len)));
+    }
+    let n = data.len().min(6);
+    if data[..n] == b"PROXY "[..n] {
+        return match data.windows(2).position(|w| w == b"\r\n") {
+            Some(pos) => Ok(Some((parse_v1(&String::from_utf8_lossy(&data[..pos])), pos + 2))),
+            // v1头部最长107字节
+            None if data.len() < 107 => Ok(None),
+            None => Err(invalid_header("proxy protocol v1 header too long")),
+        };
+    }
+    Err(invalid_header("missing proxy protocol header"))
+}
+
+// 读取PROXY protocol头，返回(源地址,目标地址)和多读出来的数据
+pub async fn read_header<S: AsyncRead + Unpin>(stream:&mut S) -> tokio::io::Result<(Option<PeerAddrs>,Vec<u8>)> {
+    let mut data = vec![];
+    let mut buf = [0u8;512];
+    loop {
+        if !data.is_empty() {
+            if let Some((addrs,len)) = parse_header(&data)? {
+                return Ok((addrs, data.split_off(len)));
+            }
+        }
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Err(tokio::io::Error::new(tokio::io::ErrorKind::UnexpectedEof, "connection closed while reading proxy protocol header"));
+        }
+        data.extend_from_slice(&buf[..n]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs(src:&str,dst:&str) -> PeerAddrs {
+        (src.parse().unwrap(), dst.parse().unwrap())
+    }
+
+    #[test]
+    fn parse_v1_header() {
+        let data = b"PROXY TCP4 1.2.3.4 5.6.7.8 1000 443\r\nGET";
+        let (peer,len) = parse_header(data).unwrap().unwrap();
+        assert_eq!(peer, Some(addrs("1.2.3.4:1000", "5.6.7.8:443")));
+        assert_eq!(&data[len..], b"GET");
+        let (peer,_) = parse_header(b"PROXY TCP6 ::1 ::2 1000 443\r\n").unwrap().unwrap();
+        assert_eq!(peer, Some(addrs("[::1]:1000", "[::2]:443")));
+        let (peer,len) = parse_header(b"PROXY UNKNOWN\r\n").unwrap().unwrap();
+        assert_eq!((peer,len), (None, 15));
+    }
+
+    #[test]
+    fn parse_v2_header() {
+        for peer in [addrs("1.2.3.4:1000", "5.6.7.8:443"), addrs("[::1]:1000", "[::2]:443")] {
+            let mut data = encode_header(ProxyProtocol::V2, Some(peer));
+            let len = data.len();
+            data.extend_from_slice(b"GET");
+            assert_eq!(parse_header(&data).unwrap().unwrap(), (Some(peer), len));
+        }
+        // LOCAL命令没有地址
+        let data = encode_header(ProxyProtocol::V2, None);
+        assert_eq!(parse_header(&data).unwrap().unwrap(), (None, 16));
+    }
+
+    #[test]
+    fn parse_split_header() {
+        let peer = addrs("1.2.3.4:1000", "5.6.7.8:443");
+        for version in [ProxyProtocol::V1, ProxyProtocol::V2] {
+            let data = encode_header(version, Some(peer));
+            for n in 1..data.len() {
+                assert!(parse_header(&data[..n]).unwrap().is_none(), "{:?} {}", version, n);
+            }
+            assert_eq!(parse_header(&data).unwrap().unwrap(), (Some(peer), data.len()));
+        }
+    }
+
+    #[test]
+    fn parse_invalid_header() {
+        assert!(parse_header(b"GET / HTTP/1.1\r\n").is_err());
+        assert!(parse_header(b"PRO").unwrap().is_none());
+        let mut long = b"PROXY ".to_vec();
+        long.resize(200, b'1');
+        assert!(parse_header(&long).is_err());
+        let mut data = encode_header(ProxyProtocol::V2, None);
+        data[12] = 0x22;
+        assert!(parse_header(&data).is_err());
+    }
+
+    #[tokio::test]
+    async fn read_split_and_truncated_header() {
+        let peer = addrs("1.2.3.4:1000", "5.6.7.8:443");
+        let header = encode_header(ProxyProtocol::V1, Some(peer));
+        let (mut client,mut server) = tokio::io::duplex(64);
+        let write = header.clone();
+        tokio::spawn(async move {
+            use tokio::io::AsyncWriteExt;
+            for chunk in write.chunks(5) {
+                client.write_all(chunk).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+            client.write_all(b"data").await.unwrap();
+        });
+        let (addrs,rest) = read_header(&mut server).await.unwrap();
+        assert_eq!(addrs, Some(peer));
+        assert!(b"data".starts_with(&rest));
+
+        let mut truncated = &header[..header.len() - 1];
+        let e = read_header(&mut truncated).await.unwrap_err();
+        assert_eq!(e.kind(), tokio::io::ErrorKind::UnexpectedEof);
+    }
+}
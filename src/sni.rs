@@ -0,0 +1,181 @@
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+// ClientHello可能分成多个tls记录
+const MAX_HELLO_LEN: usize = 65536;
+
+fn invalid_hello(msg:&str) -> tokio::io::Error {
+    tokio::io::Error::new(tokio::io::ErrorKind::InvalidData, msg.to_string())
+}
+
+struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self,n:usize) -> Option<&'a [u8]> {
+        let r = self.data.get(self.pos..self.pos + n)?;
+        self.pos += n;
+        Some(r)
+    }
+
+    fn u8(&mut self) -> Option<usize> {
+        self.take(1).map(|b| b[0] as usize)
+    }
+
+    fn u16(&mut self) -> Option<usize> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]) as usize)
+    }
+}
+
+// 从ClientHello消息中取出server_name扩展
+fn parse_sni(hello:&[u8]) -> Option<String> {
+    let mut c = Cursor { data: hello, pos: 0 };
+    if c.u8()? != 1 {
+        return None;
+    }
+    c.take(3)?;
+    // 版本和随机数
+    c.take(2 + 32)?;
+    let n = c.u8()?;
+    c.take(n)?;
+    let n = c.u16()?;
+    c.take(n)?;
+    let n = c.u8()?;
+    c.take(n)?;
+    let n = c.u16()?;
+    let mut ext = Cursor { data: c.take(n)?, pos: 0 };
+    while let (Some(ext_type),Some(len)) = (ext.u16(),ext.u16()) {
+        let body = ext.take(len)?;
+        if ext_type != 0 {
+            continue;
+        }
+        let mut names = Cursor { data: body, pos: 0 };
+        names.u16()?;
+        while let Some(name_type) = names.u8() {
+            let n = names.u16()?;
+            let name = names.take(n)?;
+            if name_type == 0 {
+                return Some(String::from_utf8_lossy(name).to_lowercase());
+            }
+        }
+    }
+    None
+}
+
+// 读取完整的ClientHello，返回sni和已经读出的原始数据
+pub async fn read_client_hello<S: AsyncRead + Unpin>(stream:&mut S) -> tokio::io::Result<(Option<String>,Vec<u8>)> {
+    let mut data = vec![];
+    let mut hello = vec![];
+    let mut buf = [0u8;4096];
+    let mut pos = 0;
+    loop {
+        // 解析已经完整读到的记录
+        while data.len() >= pos + 5 {
+            if data[pos] != 0x16 {
+                return Err(invalid_hello("not a tls handshake"));
+            }
+            let len = u16::from_be_bytes([data[pos + 3], data[pos + 4]]) as usize;
+            if data.len() < pos + 5 + len {
+                break;
+            }
+            hello.extend_from_slice(&data[pos + 5..pos + 5 + len]);
+            pos += 5 + len;
+        }
+        if hello.len() >= 4 {
+            let len = u32::from_be_bytes([0, hello[1], hello[2], hello[3]]) as usize;
+            if hello.len() >= 4 + len {
+                return Ok((parse_sni(&hello[..4 + len]), data));
+            }
+        }
+        if data.len() > MAX_HELLO_LEN {
+            return Err(invalid_hello("tls client hello too large"));
+        }
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Err(tokio::io::Error::new(tokio::io::ErrorKind::UnexpectedEof, "connection closed while reading tls client hello"));
+        }
+        data.extend_from_slice(&buf[..n]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 构造一个只有必要字段的ClientHello握手消息
+    fn client_hello(sni:Option<&str>) -> Vec<u8> {
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0; 32]);
+        body.push(0);
+        body.extend_from_slice(&[0x00, 0x02, 0x13, 0x01]);
+        body.extend_from_slice(&[0x01, 0x00]);
+        let mut ext = vec![];
+        // supported_versions
+        ext.extend_from_slice(&[0x00, 0x2b, 0x00, 0x03, 0x02, 0x03, 0x04]);
+        if let Some(name) = sni {
+            let n = name.len() as u16;
+            ext.extend_from_slice(&[0x00, 0x00]);
+            ext.extend_from_slice(&(n + 5).to_be_bytes());
+            ext.extend_from_slice(&(n + 3).to_be_bytes());
+            ext.push(0);
+            ext.extend_from_slice(&n.to_be_bytes());
+            ext.extend_from_slice(name.as_bytes());
+        }
+        body.extend_from_slice(&(ext.len() as u16).to_be_bytes());
+        body.extend_from_slice(&ext);
+        let mut hello = vec![1];
+        hello.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        hello.extend_from_slice(&body);
+        hello
+    }
+
+    // 按fragment大小分成多个tls记录
+    fn records(hello:&[u8],fragment:usize) -> Vec<u8> {
+        let mut data = vec![];
+        for chunk in hello.chunks(fragment) {
+            data.extend_from_slice(&[0x16, 0x03, 0x01]);
+            data.extend_from_slice(&(chunk.len() as u16).to_be_bytes());
+            data.extend_from_slice(chunk);
+        }
+        data
+    }
+
+    #[test]
+    fn parse_hello() {
+        assert_eq!(parse_sni(&client_hello(Some("Example.COM"))), Some("example.com".to_string()));
+        assert_eq!(parse_sni(&client_hello(None)), None);
+        let hello = client_hello(Some("example.com"));
+        assert_eq!(parse_sni(&hello[..hello.len() - 1]), None);
+        assert_eq!(parse_sni(&hello[..10]), None);
+    }
+
+    #[tokio::test]
+    async fn read_split_hello() {
+        let data = records(&client_hello(Some("example.com")), 20);
+        let (mut client,mut server) = tokio::io::duplex(64);
+        let write = data.clone();
+        tokio::spawn(async move {
+            use tokio::io::AsyncWriteExt;
+            for chunk in write.chunks(7) {
+                client.write_all(chunk).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+        });
+        let (sni,raw) = read_client_hello(&mut server).await.unwrap();
+        assert_eq!(sni.as_deref(), Some("example.com"));
+        assert_eq!(raw, data);
+
+        let data = records(&client_hello(None), 1000);
+        assert_eq!(read_client_hello(&mut &data[..]).await.unwrap().0, None);
+    }
+
+    #[tokio::test]
+    async fn read_truncated_hello() {
+        let data = records(&client_hello(Some("example.com")), 20);
+        let e = read_client_hello(&mut &data[..data.len() - 1]).await.unwrap_err();
+        assert_eq!(e.kind(), tokio::io::ErrorKind::UnexpectedEof);
+        let e = read_client_hello(&mut &b"GET / HTTP/1.1\r\n\r\n"[..]).await.unwrap_err();
+        assert_eq!(e.kind(), tokio::io::ErrorKind::InvalidData);
+    }
+}
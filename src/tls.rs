@@ -0,0 +1,162 @@
+use std::{net::IpAddr, sync::Arc};
+
+use tokio_rustls::{
+    rustls::{
+        self,
+        client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        crypto::{ring::default_provider, verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+        server::danger::{ClientCertVerified, ClientCertVerifier},
+        DigitallySignedStruct, DistinguishedName, SignatureScheme,
+    },
+    TlsAcceptor, TlsConnector,
+};
+
+use crate::{normalize_fingerprint, ClientTlsConfig, ServerTlsConfig};
+
+fn tls_error(msg:String) -> tokio::io::Error {
+    tokio::io::Error::new(tokio::io::ErrorKind::InvalidInput, msg)
+}
+
+// 证书指纹为DER的sha256，小写hex
+pub fn fingerprint(der:&[u8]) -> String {
+    ring::digest::digest(&ring::digest::SHA256, der)
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn load_certs(path:&str) -> tokio::io::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|iter| iter.collect::<Result<Vec<_>,_>>())
+        .map_err(|e| tls_error(format!("load cert {}: {}", path, e)))?;
+    if certs.is_empty() {
+        return Err(tls_error(format!("no certificate found in {}", path)));
+    }
+    Ok(certs)
+}
+
+fn load_key(path:&str) -> tokio::io::Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path).map_err(|e| tls_error(format!("load key {}: {}", path, e)))
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(default_provider())
+}
+
+// 只比较服务端证书指纹，不校验证书链和域名
+#[derive(Debug)]
+struct PinnedServerVerifier {
+    fingerprint: String,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedServerVerifier {
+    fn verify_server_cert(&self, end_entity: &CertificateDer<'_>, _intermediates: &[CertificateDer<'_>], _server_name: &ServerName<'_>, _ocsp_response: &[u8], _now: UnixTime) -> Result<ServerCertVerified, rustls::Error> {
+        let fp = fingerprint(end_entity);
+        if fp == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(format!("server certificate fingerprint mismatch: {}", fp)))
+        }
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+// 客户端证书可选，是否有效由tunnel配置的client_fingerprint决定
+#[derive(Debug)]
+struct AnyClientCertVerifier {
+    provider: Arc<CryptoProvider>,
+}
+
+impl ClientCertVerifier for AnyClientCertVerifier {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn client_auth_mandatory(&self) -> bool {
+        false
+    }
+
+    fn verify_client_cert(&self, _end_entity: &CertificateDer<'_>, _intermediates: &[CertificateDer<'_>], _now: UnixTime) -> Result<ClientCertVerified, rustls::Error> {
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+pub fn server_acceptor(conf:&ServerTlsConfig) -> tokio::io::Result<TlsAcceptor> {
+    let provider = provider();
+    let verifier = Arc::new(AnyClientCertVerifier { provider: provider.clone() });
+    let config = rustls::ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| tls_error(e.to_string()))?
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(load_certs(&conf.cert)?, load_key(&conf.key)?)
+        .map_err(|e| tls_error(e.to_string()))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+pub fn client_connector(conf:&ClientTlsConfig) -> tokio::io::Result<TlsConnector> {
+    let provider = provider();
+    let builder = rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| tls_error(e.to_string()))?;
+    let builder = if let Some(fp) = &conf.fingerprint {
+        builder.dangerous().with_custom_certificate_verifier(Arc::new(PinnedServerVerifier {
+            fingerprint: normalize_fingerprint(fp),
+            provider,
+        }))
+    } else if let Some(ca) = &conf.ca {
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in load_certs(ca)? {
+            roots.add(cert).map_err(|e| tls_error(format!("load ca {}: {}", ca, e)))?;
+        }
+        builder.with_root_certificates(roots)
+    } else {
+        return Err(tls_error("tls requires fingerprint or ca".to_string()));
+    };
+    let config = match (&conf.cert, &conf.key) {
+        (Some(cert), Some(key)) => builder
+            .with_client_auth_cert(load_certs(cert)?, load_key(key)?)
+            .map_err(|e| tls_error(e.to_string()))?,
+        _ => builder.with_no_client_auth(),
+    };
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+pub fn server_name(name:&str) -> tokio::io::Result<ServerName<'static>> {
+    if let Ok(ip) = name.parse::<IpAddr>() {
+        return Ok(ServerName::IpAddress(ip.into()));
+    }
+    ServerName::try_from(name.to_string()).map_err(|e| tls_error(format!("invalid server name {}: {}", name, e)))
+}
+
+pub fn peer_fingerprint<S>(stream:&tokio_rustls::server::TlsStream<S>) -> Option<String> {
+    let (_, conn) = stream.get_ref();
+    conn.peer_certificates()
+        .and_then(|certs| certs.first())
+        .map(|cert| fingerprint(cert))
+}
@@ -0,0 +1,302 @@
+use std::{pin::Pin, task::{ready, Context, Poll}};
+
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::http::{base64_encode, http_header, read_http_head};
+
+const WS_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_FRAME_LEN: u64 = 16 * 1024 * 1024;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+
+fn accept_key(key:&str) -> String {
+    let mut sha1 = sha1_smol::Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(WS_GUID.as_bytes());
+    base64_encode(&sha1.digest().bytes())
+}
+
+fn invalid_data(msg:&str) -> tokio::io::Error {
+    tokio::io::Error::new(tokio::io::ErrorKind::InvalidData, msg.to_string())
+}
+
+// websocket二进制消息承载隧道的字节流，每次写入封装成一个消息
+pub struct WsStream<S> {
+    inner: S,
+    // 客户端发送的帧必须加掩码
+    mask: bool,
+    rbuf: Vec<u8>,
+    payload: Vec<u8>,
+    payload_pos: usize,
+    wbuf: Vec<u8>,
+    wpos: usize,
+    closed: bool,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> WsStream<S> {
+    fn new(inner:S,mask:bool,rbuf:Vec<u8>) -> Self {
+        WsStream {
+            inner,
+            mask,
+            rbuf,
+            payload: vec![],
+            payload_pos: 0,
+            wbuf: vec![],
+            wpos: 0,
+            closed: false,
+        }
+    }
+
+    fn encode_frame(&mut self,opcode:u8,data:&[u8]) {
+        self.wbuf.push(0x80 | opcode);
+        let mask_bit = if self.mask { 0x80 } else { 0 };
+        if data.len() < 126 {
+            self.wbuf.push(mask_bit | data.len() as u8);
+        } else if data.len() <= u16::MAX as usize {
+            self.wbuf.push(mask_bit | 126);
+            self.wbuf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        } else {
+            self.wbuf.push(mask_bit | 127);
+            self.wbuf.extend_from_slice(&(data.len() as u64).to_be_bytes());
+        }
+        if self.mask {
+            let mask_key: [u8;4] = rand::random();
+            self.wbuf.extend_from_slice(&mask_key);
+            self.wbuf.extend(data.iter().enumerate().map(|(i,b)| b ^ mask_key[i % 4]));
+        } else {
+            self.wbuf.extend_from_slice(data);
+        }
+    }
+
+    // 从rbuf中解析一个完整的帧，返回(opcode,payload)
+    fn parse_frame(&mut self) -> tokio::io::Result<Option<(u8,Vec<u8>)>> {
+        if self.rbuf.len() < 2 {
+            return Ok(None);
+        }
+        let opcode = self.rbuf[0] & 0x0f;
+        let masked = self.rbuf[1] & 0x80 != 0;
+        let mut offset = 2;
+        let len = match self.rbuf[1] & 0x7f {
+            126 => {
+                if self.rbuf.len() < 4 {
+                    return Ok(None);
+                }
+                offset = 4;
+                u16::from_be_bytes([self.rbuf[2], self.rbuf[3]]) as u64
+            }
+            127 => {
+                if self.rbuf.len() < 10 {
+                    return Ok(None);
+                }
+                offset = 10;
+                let mut len_bytes = [0u8;8];
+                len_bytes.copy_from_slice(&self.rbuf[2..10]);
+                u64::from_be_bytes(len_bytes)
+            }
+            n => n as u64,
+        };
+        if len > MAX_FRAME_LEN {
+            return Err(invalid_data("websocket frame too large"));
+        }
+        let mut mask_key = [0u8;4];
+        if masked {
+            if self.rbuf.len() < offset + 4 {
+                return Ok(None);
+            }
+            mask_key.copy_from_slice(&self.rbuf[offset..offset + 4]);
+            offset += 4;
+        }
+        let end = offset + len as usize;
+        if self.rbuf.len() < end {
+            return Ok(None);
+        }
+        let mut payload: Vec<u8> = self.rbuf[offset..end].to_vec();
+        if masked {
+            for (i,b) in payload.iter_mut().enumerate() {
+                *b ^= mask_key[i % 4];
+            }
+        }
+        self.rbuf.drain(..end);
+        Ok(Some((opcode,payload)))
+    }
+
+    fn poll_write_buffered(&mut self,cx:&mut Context<'_>) -> Poll<tokio::io::Result<()>> {
+        while self.wpos < self.wbuf.len() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.wbuf[self.wpos..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(tokio::io::ErrorKind::WriteZero.into()));
+            }
+            self.wpos += n;
+        }
+        self.wbuf.clear();
+        self.wpos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WsStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<tokio::io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.payload_pos < this.payload.len() {
+                let n = (this.payload.len() - this.payload_pos).min(buf.remaining());
+                buf.put_slice(&this.payload[this.payload_pos..this.payload_pos + n]);
+                this.payload_pos += n;
+                return Poll::Ready(Ok(()));
+            }
+            if this.closed {
+                return Poll::Ready(Ok(()));
+            }
+            match this.parse_frame()? {
+                Some((opcode,payload)) => {
+                    match opcode {
+                        OP_CONTINUATION | OP_TEXT | OP_BINARY => {
+                            this.payload = payload;
+                            this.payload_pos = 0;
+                        }
+                        OP_CLOSE => this.closed = true,
+                        // pong在下次写入时一起发出
+                        OP_PING => this.encode_frame(0xA, &payload),
+                        _ => {}
+                    }
+                }
+                None => {
+                    let mut tmp = [0u8;4096];
+                    let mut read_buf = ReadBuf::new(&mut tmp);
+                    ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read_buf))?;
+                    if read_buf.filled().is_empty() {
+                        this.closed = true;
+                    } else {
+                        this.rbuf.extend_from_slice(read_buf.filled());
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WsStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<tokio::io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buffered(cx))?;
+        this.encode_frame(OP_BINARY, buf);
+        // 数据已进入缓冲区，剩余部分由flush写出
+        if let Poll::Ready(Err(e)) = this.poll_write_buffered(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<tokio::io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buffered(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<tokio::io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buffered(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+pub async fn ws_connect<S: AsyncRead + AsyncWrite + Unpin>(mut stream:S,host:&str,path:&str) -> tokio::io::Result<WsStream<S>> {
+    let key = base64_encode(&rand::random::<[u8;16]>());
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        path, host, key
+    );
+    stream.write_all(request.as_bytes()).await?;
+    let (head,rest) = read_http_head(&mut stream).await?;
+    let status_ok = head.split(' ').nth(1) == Some("101");
+    if !status_ok || http_header(&head, "Sec-WebSocket-Accept") != Some(&accept_key(&key)) {
+        return Err(invalid_data(&format!("websocket handshake failed: {}", head.lines().next().unwrap_or(""))));
+    }
+    Ok(WsStream::new(stream, true, rest))
+}
+
+pub async fn ws_accept<S: AsyncRead + AsyncWrite + Unpin>(mut stream:S) -> tokio::io::Result<WsStream<S>> {
+    let (head,rest) = read_http_head(&mut stream).await?;
+    let key = match http_header(&head, "Sec-WebSocket-Key") {
+        Some(key) if http_header(&head, "Upgrade").is_some_and(|v| v.eq_ignore_ascii_case("websocket")) => key,
+        _ => {
+            let _ = stream.write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").await;
+            return Err(invalid_data("not a websocket upgrade request"));
+        }
+    };
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    );
+    stream.write_all(response.as_bytes()).await?;
+    Ok(WsStream::new(stream, false, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, AsyncReadExt, DuplexStream};
+
+    fn stream(mask:bool) -> WsStream<DuplexStream> {
+        WsStream::new(duplex(64).0, mask, vec![])
+    }
+
+    #[test]
+    fn accept_key_rfc() {
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn parse_frames() {
+        for mask in [true, false] {
+            for len in [0, 5, 125, 126, 300, 70000] {
+                let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+                let mut w = stream(mask);
+                w.encode_frame(OP_BINARY, &data);
+                let frame = std::mem::take(&mut w.wbuf);
+                let mut r = stream(!mask);
+                // 分段到达时数据不完整返回None
+                for b in frame[..frame.len() - 1].iter() {
+                    r.rbuf.push(*b);
+                    assert!(r.parse_frame().unwrap().is_none());
+                }
+                r.rbuf.push(frame[frame.len() - 1]);
+                r.rbuf.extend_from_slice(&[0x88, 0x00]);
+                assert_eq!(r.parse_frame().unwrap(), Some((OP_BINARY, data)));
+                assert_eq!(r.parse_frame().unwrap(), Some((OP_CLOSE, vec![])));
+                assert!(r.rbuf.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn parse_frame_too_large() {
+        let mut r = stream(false);
+        r.rbuf.extend_from_slice(&[0x82, 127]);
+        r.rbuf.extend_from_slice(&(MAX_FRAME_LEN + 1).to_be_bytes());
+        assert!(r.parse_frame().is_err());
+    }
+
+    #[tokio::test]
+    async fn connect_and_accept() {
+        let (client,server) = duplex(1024);
+        let server = tokio::spawn(async move {
+            let mut ws = ws_accept(server).await.unwrap();
+            let mut buf = [0u8;5];
+            ws.read_exact(&mut buf).await.unwrap();
+            ws.write_all(&buf).await.unwrap();
+            ws.flush().await.unwrap();
+        });
+        let mut ws = ws_connect(client, "example.com", "/tunnel").await.unwrap();
+        ws.write_all(b"hello").await.unwrap();
+        ws.flush().await.unwrap();
+        let mut buf = vec![];
+        ws.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"hello");
+        server.await.unwrap();
+    }
+}